@@ -0,0 +1,48 @@
+use clap::{Parser, Subcommand};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+pub const DEFAULT_OSC_PORT: u16 = 9000;
+
+#[derive(Parser)]
+#[command(version, about = "OWO vest integration for VRChat")]
+pub struct Cli {
+    /// Config file to use instead of the one in the user config directory
+    #[arg(long, env = "VRC_OWO_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// IP address of the OWO application, instead of the one saved in the profile
+    #[arg(long, env = "VRC_OWO_IP")]
+    pub ip: Option<String>,
+
+    /// Profile to use for this session, instead of the saved or avatar-bound one
+    #[arg(long, env = "VRC_OWO_PROFILE")]
+    pub profile: Option<String>,
+
+    /// IP address of the machine running VRChat, detected automatically if not set
+    #[arg(long, env = "VRC_OWO_OSC_IP")]
+    pub osc_ip: Option<IpAddr>,
+
+    /// Port VRChat receives OSC messages on, used for chatbox messages
+    #[arg(long, env = "VRC_OWO_OSC_PORT", default_value_t = DEFAULT_OSC_PORT)]
+    pub osc_port: u16,
+
+    /// Run without opening the window, always the case when built without the gui feature
+    #[arg(long, env = "VRC_OWO_HEADLESS")]
+    pub headless: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Print the config that would be used, including migrations, and exit
+    PrintConfig,
+    /// Check the config file for errors and exit
+    ValidateConfig,
+    /// Write a profile to a .json or .toml file to share it, the active one unless --profile is set
+    ExportProfile { path: PathBuf },
+    /// Add the profile from a .json or .toml file to the config
+    ImportProfile { path: PathBuf },
+}
@@ -1,49 +1,1563 @@
+use crate::curve::{ResponseCurve, ResponseCurves};
+use crate::envelope::{Envelope, Envelopes};
+use crate::gesture::{default_gestures, Gesture};
+use crate::muscle::{
+    body_point, default_muscle_mapping, interaction_name, muscle_name, parse_muscle, Adaptation,
+    ContactPoint, DepthVelocityRange, InstanceMappings, IntensityLimits, IntensityScales,
+    InteractionType, MixPolicy, MuscleLink, MuscleMapping, ParameterMapping, VelocityRange,
+    VelocitySource, DEFAULT_INTENSITY_GRAB, DEFAULT_INTENSITY_IMPACT, DEFAULT_INTENSITY_PROJECTILE,
+    DEFAULT_INTENSITY_STAB, DEFAULT_INTENSITY_TOUCH, INTERACTION_TYPES, MAX_INTENSITY, MAX_SCALE,
+    MAX_WEIGHT,
+};
+use crate::osc::DEFAULT_PREFIX;
+use crate::owo_thread::Connection;
+use crate::smoothing::Smoothing;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub const CONFIG_FILE: &str = "muscle_config.json";
+pub const CONFIG_VERSION: u32 = 5;
+pub const DEFAULT_PROFILE: &str = "Default";
+pub const BACKUP_COUNT: usize = 5;
 
-#[derive(Serialize, Deserialize)]
-pub struct MuscleConfig {
-    pub name: String,
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+// The config as last loaded or saved, used to tell our own writes apart from external edits
+static LAST_SAVED: Mutex<Option<String>> = Mutex::new(None);
+
+// Backups restored since the config was last loaded or saved, each restore goes one further back
+static RESTORED: Mutex<usize> = Mutex::new(0);
+
+// Numbers the temporary files of saves, so two saves never write to the same file
+static SAVE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// MIGRATIONS[n] upgrades a config from version n to version n + 1
+const MIGRATIONS: [fn(&mut Value); CONFIG_VERSION as usize] = [
+    migrate_v0_to_v1,
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+    migrate_v3_to_v4,
+    migrate_v4_to_v5,
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    UnsupportedVersion(u32),
+    UnknownMuscle {
+        name: String,
+        muscle: String,
+    },
+    IntensityOutOfRange {
+        name: String,
+        field: &'static str,
+        value: u8,
+    },
+    UnknownProfile(String),
+    DuplicateProfile(String),
+    EmptyProfileName,
+    LastProfile,
+    InvalidPrefix(String),
+    UnknownPrefix(String),
+    DuplicatePrefix(String),
+    LastInstance,
+    UnknownParameter(String),
+    DuplicateParameter(String),
+    InvalidParameterName(String),
+    WeightOutOfRange {
+        name: String,
+        muscle: String,
+        weight: f32,
+    },
+    InvalidCurve {
+        name: String,
+        interaction: &'static str,
+        message: String,
+    },
+    InvalidLimits {
+        name: String,
+        message: String,
+    },
+    InvalidAdaptation {
+        name: String,
+        message: String,
+    },
+    InvalidSmoothing {
+        name: String,
+        message: String,
+    },
+    InvalidContactPoint {
+        name: String,
+        message: String,
+    },
+    UnknownBodyPoint(String),
+    ScaleOutOfRange {
+        name: &'static str,
+        value: f32,
+    },
+    InvalidVelocityRange(String),
+    InvalidDepthVelocityRange(String),
+    InvalidEnvelope {
+        interaction: &'static str,
+        message: String,
+    },
+    DuplicateGesture(String),
+    InvalidGesture {
+        name: String,
+        message: String,
+    },
+    InvalidProfileFile(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "Error reading config file: {}", e),
+            ConfigError::Parse {
+                line,
+                column,
+                message,
+            } => {
+                if *line == 0 {
+                    write!(f, "Invalid config file: {}", message)
+                } else {
+                    write!(
+                        f,
+                        "Invalid config file at line {}, column {}: {}",
+                        line, column, message
+                    )
+                }
+            }
+            ConfigError::UnsupportedVersion(version) => write!(
+                f,
+                "Config file version {} is newer than supported version {}",
+                version, CONFIG_VERSION
+            ),
+            ConfigError::UnknownMuscle { name, muscle } => {
+                write!(f, "Unknown muscle '{}' for {}", muscle, name)
+            }
+            ConfigError::IntensityOutOfRange { name, field, value } => write!(
+                f,
+                "{} of {} is {}, maximum is {}",
+                field, name, value, MAX_INTENSITY
+            ),
+            ConfigError::UnknownProfile(name) => write!(f, "Unknown profile '{}'", name),
+            ConfigError::DuplicateProfile(name) => {
+                write!(f, "A profile named '{}' already exists", name)
+            }
+            ConfigError::EmptyProfileName => write!(f, "Profile name cannot be empty"),
+            ConfigError::LastProfile => write!(f, "Cannot delete the last profile"),
+            ConfigError::InvalidPrefix(prefix) => write!(
+                f,
+                "Invalid parameter prefix '{}', it cannot be empty or start or end with '/'",
+                prefix
+            ),
+            ConfigError::UnknownPrefix(prefix) => {
+                write!(f, "Unknown parameter prefix '{}'", prefix)
+            }
+            ConfigError::DuplicatePrefix(prefix) => {
+                write!(f, "Parameter prefix '{}' is already used", prefix)
+            }
+            ConfigError::LastInstance => {
+                write!(f, "Cannot remove the last parameter prefix of a profile")
+            }
+            ConfigError::UnknownParameter(name) => write!(f, "Unknown parameter '{}'", name),
+            ConfigError::DuplicateParameter(name) => {
+                write!(f, "A parameter named '{}' already exists", name)
+            }
+            ConfigError::InvalidParameterName(name) => write!(
+                f,
+                "Invalid parameter name '{}', it cannot be empty or contain '/'",
+                name
+            ),
+            ConfigError::WeightOutOfRange {
+                name,
+                muscle,
+                weight,
+            } => write!(
+                f,
+                "Weight of {} for {} is {}, it must be between 0 and {}",
+                muscle, name, weight, MAX_WEIGHT
+            ),
+            ConfigError::InvalidLimits { name, message } => {
+                write!(f, "Invalid limits for {}: {}", name, message)
+            }
+            ConfigError::InvalidAdaptation { name, message } => {
+                write!(f, "Invalid adaptation for {}: {}", name, message)
+            }
+            ConfigError::InvalidSmoothing { name, message } => {
+                write!(f, "Invalid smoothing for {}: {}", name, message)
+            }
+            ConfigError::InvalidContactPoint { name, message } => {
+                write!(f, "Invalid contact point for {}: {}", name, message)
+            }
+            ConfigError::UnknownBodyPoint(point) => write!(f, "Unknown body point '{}'", point),
+            ConfigError::InvalidCurve {
+                name,
+                interaction,
+                message,
+            } => write!(
+                f,
+                "Invalid {} response curve for {}: {}",
+                interaction, name, message
+            ),
+            ConfigError::ScaleOutOfRange { name, value } => write!(
+                f,
+                "{} scale is {}, it must be between 0 and {}",
+                name, value, MAX_SCALE
+            ),
+            ConfigError::InvalidVelocityRange(message) => {
+                write!(f, "Invalid velocity range: {}", message)
+            }
+            ConfigError::InvalidDepthVelocityRange(message) => {
+                write!(f, "Invalid depth velocity range: {}", message)
+            }
+            ConfigError::InvalidEnvelope {
+                interaction,
+                message,
+            } => write!(f, "Invalid {} envelope: {}", interaction, message),
+            ConfigError::DuplicateGesture(name) => {
+                write!(f, "A gesture named '{}' already exists", name)
+            }
+            ConfigError::InvalidGesture { name, message } => {
+                write!(f, "Invalid gesture '{}': {}", name, message)
+            }
+            ConfigError::InvalidProfileFile(message) => {
+                write!(f, "Invalid profile file: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(e: serde_json::Error) -> Self {
+        // serde_json appends the position to its message, we report it separately
+        let message = e.to_string();
+        let position = format!(" at line {} column {}", e.line(), e.column());
+        ConfigError::Parse {
+            line: e.line(),
+            column: e.column(),
+            message: message
+                .strip_suffix(&position)
+                .unwrap_or(&message)
+                .to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MuscleLinkConfig {
     pub muscle: String,
+    pub weight: f32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ParameterConfig {
+    pub name: String,
     pub intensity_touch: u8,
     pub intensity_impact: u8,
     pub intensity_stab: u8,
+    // Configs from before projectiles existed get the default intensity
+    #[serde(default = "intensity_projectile")]
+    pub intensity_projectile: u8,
+    #[serde(default = "intensity_grab")]
+    pub intensity_grab: u8,
+    // Linear for configs from before curves existed
+    #[serde(default)]
+    pub curves: ResponseCurves,
+    // No limits for configs from before they existed
+    #[serde(default)]
+    pub limits: IntensityLimits,
+    #[serde(default)]
+    pub adaptation: Adaptation,
+    #[serde(default)]
+    pub smoothing: Smoothing,
+    // Drives the muscles around this point in addition to the linked ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub point: Option<ContactPoint>,
+    pub muscles: Vec<MuscleLinkConfig>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Config {
-    pub muscles: Vec<MuscleConfig>,
+// One prefab on the avatar, its parameters are /avatar/parameters/<prefix>/<parameter>/...
+#[derive(Clone, Serialize, Deserialize)]
+pub struct InstanceConfig {
+    pub prefix: String,
+    pub parameters: Vec<ParameterConfig>,
+    #[serde(default)]
+    pub velocity_source: VelocitySource,
+}
+
+// Describes a profile when it is shared with others
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ProfileMetadata {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub author: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub avatar: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub notes: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub instances: Vec<InstanceConfig>,
     pub ip_address: Option<String>,
+    #[serde(default)]
+    pub metadata: ProfileMetadata,
+    #[serde(default)]
+    pub velocity_range: VelocityRange,
+    #[serde(default)]
+    pub depth_velocity_range: DepthVelocityRange,
+}
+
+impl Profile {
+    pub fn from_mapping(name: &str, mapping: &MuscleMapping, ip_address: Option<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            instances: vec![InstanceConfig::from_mapping(DEFAULT_PREFIX, mapping)],
+            ip_address,
+            metadata: ProfileMetadata::default(),
+            velocity_range: VelocityRange::default(),
+            depth_velocity_range: DepthVelocityRange::default(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (i, instance) in self.instances.iter().enumerate() {
+            if self.instances[..i]
+                .iter()
+                .any(|other| other.prefix == instance.prefix)
+            {
+                return Err(ConfigError::DuplicatePrefix(instance.prefix.clone()));
+            }
+            instance.validate()?;
+        }
+        self.velocity_range
+            .validate()
+            .map_err(ConfigError::InvalidVelocityRange)?;
+        self.depth_velocity_range
+            .validate()
+            .map_err(ConfigError::InvalidDepthVelocityRange)
+    }
+
+    pub fn set_velocity_range(&mut self, velocity_range: VelocityRange) -> Result<(), ConfigError> {
+        velocity_range
+            .validate()
+            .map_err(ConfigError::InvalidVelocityRange)?;
+        self.velocity_range = velocity_range;
+        Ok(())
+    }
+
+    pub fn set_depth_velocity_range(
+        &mut self,
+        range: DepthVelocityRange,
+    ) -> Result<(), ConfigError> {
+        range
+            .validate()
+            .map_err(ConfigError::InvalidDepthVelocityRange)?;
+        self.depth_velocity_range = range;
+        Ok(())
+    }
+
+    pub fn to_mappings(&self) -> Vec<InstanceMappings> {
+        self.instances
+            .iter()
+            .map(|instance| InstanceMappings {
+                prefix: instance.prefix.clone(),
+                mapping: instance.to_mapping(),
+                velocity_source: instance.velocity_source,
+                velocity_range: self.velocity_range,
+                depth_velocity_range: self.depth_velocity_range,
+            })
+            .collect()
+    }
+
+    pub fn instance(&self, prefix: &str) -> Option<&InstanceConfig> {
+        self.instances
+            .iter()
+            .find(|instance| instance.prefix == prefix)
+    }
+
+    pub fn instance_mut(&mut self, prefix: &str) -> Option<&mut InstanceConfig> {
+        self.instances
+            .iter_mut()
+            .find(|instance| instance.prefix == prefix)
+    }
+
+    pub fn add_instance(&mut self, prefix: &str) -> Result<(), ConfigError> {
+        self.check_new_prefix(prefix)?;
+        self.instances.push(InstanceConfig::from_mapping(
+            prefix,
+            &default_muscle_mapping(),
+        ));
+        Ok(())
+    }
+
+    pub fn rename_instance(
+        &mut self,
+        old_prefix: &str,
+        new_prefix: &str,
+    ) -> Result<(), ConfigError> {
+        self.check_new_prefix(new_prefix)?;
+        let instance = self
+            .instance_mut(old_prefix)
+            .ok_or_else(|| ConfigError::UnknownPrefix(old_prefix.to_string()))?;
+        instance.prefix = new_prefix.to_string();
+        Ok(())
+    }
+
+    pub fn remove_instance(&mut self, prefix: &str) -> Result<(), ConfigError> {
+        if self.instance(prefix).is_none() {
+            return Err(ConfigError::UnknownPrefix(prefix.to_string()));
+        }
+        if self.instances.len() == 1 {
+            return Err(ConfigError::LastInstance);
+        }
+        self.instances.retain(|instance| instance.prefix != prefix);
+        Ok(())
+    }
+
+    fn check_new_prefix(&self, prefix: &str) -> Result<(), ConfigError> {
+        check_prefix(prefix)?;
+        if self.instance(prefix).is_some() {
+            return Err(ConfigError::DuplicatePrefix(prefix.to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl InstanceConfig {
+    pub fn from_mapping(prefix: &str, mapping: &MuscleMapping) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            parameters: mapping
+                .parameters
+                .iter()
+                .map(|parameter| ParameterConfig {
+                    name: parameter.name.clone(),
+                    intensity_touch: parameter.intensity_touch,
+                    intensity_impact: parameter.intensity_impact,
+                    intensity_stab: parameter.intensity_stab,
+                    intensity_projectile: parameter.intensity_projectile,
+                    intensity_grab: parameter.intensity_grab,
+                    curves: parameter.curves.clone(),
+                    limits: parameter.limits,
+                    adaptation: parameter.adaptation,
+                    smoothing: parameter.smoothing,
+                    point: None,
+                    muscles: parameter
+                        .links
+                        .iter()
+                        .map(|link| MuscleLinkConfig {
+                            muscle: muscle_name(link.muscle),
+                            weight: link.weight,
+                        })
+                        .collect(),
+                })
+                .collect(),
+            velocity_source: VelocitySource::default(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        check_prefix(&self.prefix)?;
+        for (i, parameter) in self.parameters.iter().enumerate() {
+            check_parameter_name(&parameter.name)?;
+            if self.parameters[..i]
+                .iter()
+                .any(|other| other.name == parameter.name)
+            {
+                return Err(ConfigError::DuplicateParameter(parameter.name.clone()));
+            }
+
+            for link in &parameter.muscles {
+                if parse_muscle(&link.muscle).is_none() {
+                    return Err(ConfigError::UnknownMuscle {
+                        name: parameter.name.clone(),
+                        muscle: link.muscle.clone(),
+                    });
+                }
+                check_weight(&parameter.name, &link.muscle, link.weight)?;
+            }
+
+            for interaction_type in INTERACTION_TYPES {
+                parameter
+                    .curves
+                    .get(interaction_type)
+                    .validate()
+                    .map_err(|message| ConfigError::InvalidCurve {
+                        name: parameter.name.clone(),
+                        interaction: interaction_name(interaction_type),
+                        message,
+                    })?;
+            }
+
+            parameter
+                .limits
+                .validate()
+                .map_err(|message| ConfigError::InvalidLimits {
+                    name: parameter.name.clone(),
+                    message,
+                })?;
+
+            parameter
+                .adaptation
+                .validate()
+                .map_err(|message| ConfigError::InvalidAdaptation {
+                    name: parameter.name.clone(),
+                    message,
+                })?;
+
+            parameter
+                .smoothing
+                .validate()
+                .map_err(|message| ConfigError::InvalidSmoothing {
+                    name: parameter.name.clone(),
+                    message,
+                })?;
+
+            if let Some(point) = &parameter.point {
+                point
+                    .validate()
+                    .map_err(|message| ConfigError::InvalidContactPoint {
+                        name: parameter.name.clone(),
+                        message,
+                    })?;
+            }
+
+            for (field, value) in [
+                ("intensity_touch", parameter.intensity_touch),
+                ("intensity_impact", parameter.intensity_impact),
+                ("intensity_stab", parameter.intensity_stab),
+                ("intensity_projectile", parameter.intensity_projectile),
+                ("intensity_grab", parameter.intensity_grab),
+            ] {
+                if value > MAX_INTENSITY {
+                    return Err(ConfigError::IntensityOutOfRange {
+                        name: parameter.name.clone(),
+                        field,
+                        value,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_mapping(&self) -> MuscleMapping {
+        MuscleMapping {
+            parameters: self
+                .parameters
+                .iter()
+                .map(|parameter| ParameterMapping {
+                    name: parameter.name.clone(),
+                    intensity_touch: parameter.intensity_touch,
+                    intensity_impact: parameter.intensity_impact,
+                    intensity_stab: parameter.intensity_stab,
+                    intensity_projectile: parameter.intensity_projectile,
+                    intensity_grab: parameter.intensity_grab,
+                    curves: parameter.curves.clone(),
+                    limits: parameter.limits,
+                    adaptation: parameter.adaptation,
+                    smoothing: parameter.smoothing,
+                    links: parameter
+                        .muscles
+                        .iter()
+                        .filter_map(|link| match parse_muscle(&link.muscle) {
+                            Some(muscle) => Some(MuscleLink {
+                                muscle,
+                                weight: link.weight,
+                            }),
+                            None => {
+                                println!(
+                                    "Ignoring unknown muscle '{}' for parameter {}",
+                                    link.muscle, parameter.name
+                                );
+                                None
+                            }
+                        })
+                        .chain(parameter.point.iter().flat_map(ContactPoint::links))
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn parameter_mut(&mut self, name: &str) -> Option<&mut ParameterConfig> {
+        self.parameters
+            .iter_mut()
+            .find(|parameter| parameter.name == name)
+    }
+
+    pub fn add_parameter(&mut self, name: &str) -> Result<(), ConfigError> {
+        check_parameter_name(name)?;
+        if self
+            .parameters
+            .iter()
+            .any(|parameter| parameter.name == name)
+        {
+            return Err(ConfigError::DuplicateParameter(name.to_string()));
+        }
+        self.parameters.push(ParameterConfig {
+            name: name.to_string(),
+            intensity_touch: DEFAULT_INTENSITY_TOUCH,
+            intensity_impact: DEFAULT_INTENSITY_IMPACT,
+            intensity_stab: DEFAULT_INTENSITY_STAB,
+            intensity_projectile: DEFAULT_INTENSITY_PROJECTILE,
+            intensity_grab: DEFAULT_INTENSITY_GRAB,
+            curves: ResponseCurves::default(),
+            limits: IntensityLimits::default(),
+            adaptation: Adaptation::default(),
+            smoothing: Smoothing::default(),
+            // Parameters named after a body point are placed there right away
+            point: body_point(name),
+            muscles: Vec::new(),
+        });
+        Ok(())
+    }
+
+    pub fn remove_parameter(&mut self, name: &str) -> Result<(), ConfigError> {
+        if !self
+            .parameters
+            .iter()
+            .any(|parameter| parameter.name == name)
+        {
+            return Err(ConfigError::UnknownParameter(name.to_string()));
+        }
+        self.parameters.retain(|parameter| parameter.name != name);
+        Ok(())
+    }
+
+    // Links a parameter to a muscle, or changes the weight if they are already linked
+    pub fn link_muscle(
+        &mut self,
+        name: &str,
+        muscle: &str,
+        weight: f32,
+    ) -> Result<(), ConfigError> {
+        if parse_muscle(muscle).is_none() {
+            return Err(ConfigError::UnknownMuscle {
+                name: name.to_string(),
+                muscle: muscle.to_string(),
+            });
+        }
+        check_weight(name, muscle, weight)?;
+        let parameter = self
+            .parameter_mut(name)
+            .ok_or_else(|| ConfigError::UnknownParameter(name.to_string()))?;
+        match parameter
+            .muscles
+            .iter_mut()
+            .find(|link| link.muscle == muscle)
+        {
+            Some(link) => link.weight = weight,
+            None => parameter.muscles.push(MuscleLinkConfig {
+                muscle: muscle.to_string(),
+                weight,
+            }),
+        }
+        Ok(())
+    }
+
+    pub fn set_curve(
+        &mut self,
+        name: &str,
+        interaction_type: InteractionType,
+        curve: ResponseCurve,
+    ) -> Result<(), ConfigError> {
+        curve
+            .validate()
+            .map_err(|message| ConfigError::InvalidCurve {
+                name: name.to_string(),
+                interaction: interaction_name(interaction_type),
+                message,
+            })?;
+        let parameter = self
+            .parameter_mut(name)
+            .ok_or_else(|| ConfigError::UnknownParameter(name.to_string()))?;
+        *parameter.curves.get_mut(interaction_type) = curve;
+        Ok(())
+    }
+
+    // Places a parameter on a named body point, or removes it from the body map
+    pub fn place_parameter(&mut self, name: &str, point: Option<&str>) -> Result<(), ConfigError> {
+        let point = point
+            .map(|point| {
+                body_point(point).ok_or_else(|| ConfigError::UnknownBodyPoint(point.to_string()))
+            })
+            .transpose()?;
+        let parameter = self
+            .parameter_mut(name)
+            .ok_or_else(|| ConfigError::UnknownParameter(name.to_string()))?;
+        parameter.point = point;
+        Ok(())
+    }
+
+    pub fn unlink_muscle(&mut self, name: &str, muscle: &str) -> Result<(), ConfigError> {
+        let parameter = self
+            .parameter_mut(name)
+            .ok_or_else(|| ConfigError::UnknownParameter(name.to_string()))?;
+        parameter.muscles.retain(|link| link.muscle != muscle);
+        Ok(())
+    }
 }
 
-pub fn load_config() -> Option<Config> {
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Config {
+    pub version: u32,
+    pub active_profile: String,
+    // Profile used for avatars without a binding in avatar_profiles
+    pub default_profile: String,
+    pub profiles: Vec<Profile>,
+    // Avatar ID to profile name
+    pub avatar_profiles: BTreeMap<String, String>,
+    // Shared by all profiles, so the vest can be made gentler without editing each one
+    #[serde(default)]
+    pub intensity_scales: IntensityScales,
+    #[serde(default)]
+    pub envelopes: Envelopes,
+    #[serde(default)]
+    pub mix_policy: MixPolicy,
+    #[serde(default = "default_gestures")]
+    pub gestures: Vec<Gesture>,
+    // Bumped whenever the config changes outside the window, so it knows to refresh
+    #[serde(skip)]
+    pub revision: u64,
+    // Profile from the command line, used instead of active_profile and the avatar bindings until
+    // the user picks one. It is never saved.
+    #[serde(skip)]
+    pub profile_override: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            active_profile: DEFAULT_PROFILE.to_string(),
+            default_profile: DEFAULT_PROFILE.to_string(),
+            profiles: vec![Profile::from_mapping(
+                DEFAULT_PROFILE,
+                &default_muscle_mapping(),
+                None,
+            )],
+            avatar_profiles: BTreeMap::new(),
+            intensity_scales: IntensityScales::default(),
+            envelopes: Envelopes::default(),
+            mix_policy: MixPolicy::default(),
+            gestures: default_gestures(),
+            revision: 0,
+            profile_override: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (i, profile) in self.profiles.iter().enumerate() {
+            if self.profiles[..i]
+                .iter()
+                .any(|other| other.name == profile.name)
+            {
+                return Err(ConfigError::DuplicateProfile(profile.name.clone()));
+            }
+            profile.validate()?;
+        }
+
+        for (name, value) in self.intensity_scales.values() {
+            if !(0.0..=MAX_SCALE).contains(&value) {
+                return Err(ConfigError::ScaleOutOfRange { name, value });
+            }
+        }
+
+        for interaction_type in INTERACTION_TYPES {
+            check_envelope(interaction_type, self.envelopes.get(interaction_type))?;
+        }
+
+        // Gestures keep track of what they have seen by name
+        for (i, gesture) in self.gestures.iter().enumerate() {
+            if self.gestures[..i]
+                .iter()
+                .any(|other| other.name == gesture.name)
+            {
+                return Err(ConfigError::DuplicateGesture(gesture.name.clone()));
+            }
+            gesture
+                .validate()
+                .map_err(|message| ConfigError::InvalidGesture {
+                    name: gesture.name.clone(),
+                    message,
+                })?;
+        }
+
+        let referenced = [&self.active_profile, &self.default_profile]
+            .into_iter()
+            .chain(self.profile_override.iter())
+            .chain(self.avatar_profiles.values());
+        for name in referenced {
+            if self.profile(name).is_none() {
+                return Err(ConfigError::UnknownProfile(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    pub fn profile_mut(&mut self, name: &str) -> Option<&mut Profile> {
+        self.profiles
+            .iter_mut()
+            .find(|profile| profile.name == name)
+    }
+
+    pub fn active_profile_name(&self) -> &str {
+        self.profile_override
+            .as_deref()
+            .unwrap_or(&self.active_profile)
+    }
+
+    pub fn active_profile(&self) -> &Profile {
+        self.profile(self.active_profile_name())
+            .expect("active profile is checked by validate")
+    }
+
+    pub fn active_profile_mut(&mut self) -> &mut Profile {
+        let name = self.active_profile_name().to_string();
+        self.profile_mut(&name)
+            .expect("active profile is checked by validate")
+    }
+
+    pub fn profile_names(&self) -> Vec<String> {
+        self.profiles
+            .iter()
+            .map(|profile| profile.name.clone())
+            .collect()
+    }
+
+    pub fn profile_for_avatar(&self, avatar_id: &str) -> &str {
+        self.avatar_profiles
+            .get(avatar_id)
+            .unwrap_or(&self.default_profile)
+    }
+
+    pub fn bind_avatar(&mut self, avatar_id: &str, profile: &str) -> Result<(), ConfigError> {
+        if self.profile(profile).is_none() {
+            return Err(ConfigError::UnknownProfile(profile.to_string()));
+        }
+        self.avatar_profiles
+            .insert(avatar_id.to_string(), profile.to_string());
+        Ok(())
+    }
+
+    pub fn set_default_profile(&mut self, name: &str) -> Result<(), ConfigError> {
+        if self.profile(name).is_none() {
+            return Err(ConfigError::UnknownProfile(name.to_string()));
+        }
+        self.default_profile = name.to_string();
+        Ok(())
+    }
+
+    pub fn set_active_profile(&mut self, name: &str) -> Result<(), ConfigError> {
+        if self.profile(name).is_none() {
+            return Err(ConfigError::UnknownProfile(name.to_string()));
+        }
+        self.active_profile = name.to_string();
+        self.profile_override = None;
+        Ok(())
+    }
+
+    pub fn set_profile_override(&mut self, name: &str) -> Result<(), ConfigError> {
+        if self.profile(name).is_none() {
+            return Err(ConfigError::UnknownProfile(name.to_string()));
+        }
+        self.profile_override = Some(name.to_string());
+        Ok(())
+    }
+
+    pub fn set_envelope(
+        &mut self,
+        interaction_type: InteractionType,
+        envelope: Envelope,
+    ) -> Result<(), ConfigError> {
+        check_envelope(interaction_type, &envelope)?;
+        *self.envelopes.get_mut(interaction_type) = envelope;
+        Ok(())
+    }
+
+    pub fn create_profile(&mut self, name: &str) -> Result<(), ConfigError> {
+        self.check_new_profile_name(name)?;
+        self.profiles
+            .push(Profile::from_mapping(name, &default_muscle_mapping(), None));
+        Ok(())
+    }
+
+    pub fn clone_profile(&mut self, source: &str, name: &str) -> Result<(), ConfigError> {
+        self.check_new_profile_name(name)?;
+        let mut profile = self
+            .profile(source)
+            .cloned()
+            .ok_or_else(|| ConfigError::UnknownProfile(source.to_string()))?;
+        profile.name = name.to_string();
+        self.profiles.push(profile);
+        Ok(())
+    }
+
+    // Adds a profile from elsewhere, numbering its name if it is already taken. Returns the name.
+    pub fn add_profile(&mut self, mut profile: Profile) -> Result<String, ConfigError> {
+        if profile.name.trim().is_empty() {
+            return Err(ConfigError::EmptyProfileName);
+        }
+        profile.validate()?;
+
+        let base_name = profile.name.clone();
+        let mut number = 2;
+        while self.profile(&profile.name).is_some() {
+            profile.name = format!("{} ({})", base_name, number);
+            number += 1;
+        }
+        let name = profile.name.clone();
+        self.profiles.push(profile);
+        Ok(name)
+    }
+
+    pub fn rename_profile(&mut self, old_name: &str, new_name: &str) -> Result<(), ConfigError> {
+        self.check_new_profile_name(new_name)?;
+        let profile = self
+            .profile_mut(old_name)
+            .ok_or_else(|| ConfigError::UnknownProfile(old_name.to_string()))?;
+        profile.name = new_name.to_string();
+
+        let references = [&mut self.active_profile, &mut self.default_profile]
+            .into_iter()
+            .chain(self.profile_override.iter_mut())
+            .chain(self.avatar_profiles.values_mut());
+        for reference in references.filter(|reference| *reference == old_name) {
+            *reference = new_name.to_string();
+        }
+        Ok(())
+    }
+
+    pub fn delete_profile(&mut self, name: &str) -> Result<(), ConfigError> {
+        if self.profile(name).is_none() {
+            return Err(ConfigError::UnknownProfile(name.to_string()));
+        }
+        if self.profiles.len() == 1 {
+            return Err(ConfigError::LastProfile);
+        }
+        self.profiles.retain(|profile| profile.name != name);
+        self.avatar_profiles.retain(|_, profile| profile != name);
+        if self.default_profile == name {
+            self.default_profile = self.profiles[0].name.clone();
+        }
+        if self.active_profile == name {
+            self.active_profile = self.default_profile.clone();
+        }
+        if self.profile_override.as_deref() == Some(name) {
+            self.profile_override = None;
+        }
+        Ok(())
+    }
+
+    fn check_new_profile_name(&self, name: &str) -> Result<(), ConfigError> {
+        if name.trim().is_empty() {
+            return Err(ConfigError::EmptyProfileName);
+        }
+        if self.profile(name).is_some() {
+            return Err(ConfigError::DuplicateProfile(name.to_string()));
+        }
+        Ok(())
+    }
+}
+
+// Parameter names are the part between the prefix and /depth, /velocity/... or /type/...
+fn check_parameter_name(name: &str) -> Result<(), ConfigError> {
+    if name.trim().is_empty() || name.contains('/') {
+        return Err(ConfigError::InvalidParameterName(name.to_string()));
+    }
+    Ok(())
+}
+
+fn check_weight(name: &str, muscle: &str, weight: f32) -> Result<(), ConfigError> {
+    if !(0.0..=MAX_WEIGHT).contains(&weight) {
+        return Err(ConfigError::WeightOutOfRange {
+            name: name.to_string(),
+            muscle: muscle.to_string(),
+            weight,
+        });
+    }
+    Ok(())
+}
+
+fn check_envelope(
+    interaction_type: InteractionType,
+    envelope: &Envelope,
+) -> Result<(), ConfigError> {
+    envelope
+        .validate()
+        .map_err(|message| ConfigError::InvalidEnvelope {
+            interaction: interaction_name(interaction_type),
+            message,
+        })
+}
+
+fn intensity_projectile() -> u8 {
+    DEFAULT_INTENSITY_PROJECTILE
+}
+
+fn intensity_grab() -> u8 {
+    DEFAULT_INTENSITY_GRAB
+}
+
+fn check_prefix(prefix: &str) -> Result<(), ConfigError> {
+    if prefix.trim().is_empty() || prefix.starts_with('/') || prefix.ends_with('/') {
+        return Err(ConfigError::InvalidPrefix(prefix.to_string()));
+    }
+    Ok(())
+}
+
+// Makes a profile the live configuration, reconnecting if its connection settings differ
+pub fn apply_profile(
+    profile: &Profile,
+    muscle_mappings: &Mutex<Vec<InstanceMappings>>,
+    connection: &Mutex<Connection>,
+) {
+    *muscle_mappings.lock().unwrap() = profile.to_mappings();
+    connection
+        .lock()
+        .unwrap()
+        .set_profile_ip_address(profile.ip_address.clone());
+}
+
+pub fn load_config() -> Result<Option<Config>, ConfigError> {
     let config_path = get_config_path();
     if !config_path.exists() {
-        return None;
+        return Ok(None);
     }
 
-    match fs::read_to_string(config_path) {
-        Ok(contents) => serde_json::from_str(&contents).ok(),
-        Err(e) => {
-            println!("Error reading config file: {}", e);
-            None
-        }
+    let contents = fs::read_to_string(&config_path)?;
+    let (config, version) = parse_config(&contents)?;
+    if version == CONFIG_VERSION {
+        *LAST_SAVED.lock().unwrap() = Some(config_json(&config)?);
+        *RESTORED.lock().unwrap() = 0;
+        return Ok(Some(config));
     }
+
+    let backup_path = get_migration_backup_path(version);
+    fs::write(&backup_path, &contents)?;
+    save_config(&config)?;
+    println!(
+        "Migrated config from version {} to {} (backup at {})",
+        version,
+        CONFIG_VERSION,
+        backup_path.display()
+    );
+
+    Ok(Some(config))
+}
+
+// Like load_config, but leaves the file untouched if it needs to be migrated
+pub fn read_config() -> Result<Option<Config>, ConfigError> {
+    let config_path = get_config_path();
+    if !config_path.exists() {
+        return Ok(None);
+    }
+
+    let contents = fs::read_to_string(&config_path)?;
+    let (config, _) = parse_config(&contents)?;
+    Ok(Some(config))
+}
+
+// Parses and validates a config file, returning the config and the version it was migrated from
+pub fn parse_config(contents: &str) -> Result<(Config, u32), ConfigError> {
+    let mut value: Value = serde_json::from_str(contents)?;
+    let version = config_version(&value);
+    if version > CONFIG_VERSION {
+        return Err(ConfigError::UnsupportedVersion(version));
+    }
+
+    if version == CONFIG_VERSION {
+        let config: Config = serde_json::from_str(contents)?;
+        config.validate()?;
+        return Ok((config, version));
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut value);
+    }
+    let config: Config = serde_json::from_value(value)?;
+    config.validate()?;
+    Ok((config, version))
 }
 
 pub fn save_config(config: &Config) -> std::io::Result<()> {
     let config_path = get_config_path();
-    let json = serde_json::to_string_pretty(config)?;
-    fs::write(config_path, json)
+    let json = config_json(config)?;
+    // Writing what is already on disk would only push an older backup out
+    if LAST_SAVED.lock().unwrap().as_ref() == Some(&json) {
+        return Ok(());
+    }
+
+    replace_config_file(&config_path, &json)?;
+    *LAST_SAVED.lock().unwrap() = Some(json);
+    *RESTORED.lock().unwrap() = 0;
+    Ok(())
+}
+
+// Puts back the config as it was before the last save. The replaced config becomes the newest
+// backup like on a save, so it can be restored as well. Restoring again goes one further back.
+pub fn restore_previous_config() -> Result<Config, ConfigError> {
+    let mut restored = RESTORED.lock().unwrap();
+    let index = *restored + 1;
+    let backup_path = get_backup_path(index);
+    let contents = fs::read_to_string(&backup_path)?;
+    let (config, _) = parse_config(&contents)?;
+
+    // Take the restored backup out, so the current config fits in without dropping the oldest
+    fs::remove_file(&backup_path)?;
+    for index in index + 1..=BACKUP_COUNT {
+        let path = get_backup_path(index);
+        if path.exists() {
+            fs::rename(path, get_backup_path(index - 1))?;
+        }
+    }
+    // Backups of older versions are written back migrated
+    replace_config_file(&get_config_path(), &config_json(&config)?)?;
+    *restored += 1;
+    mark_config_saved(&config);
+    Ok(config)
+}
+
+// Writes to a temporary file first and renames it over the config, so a crash never leaves a
+// truncated file behind. The replaced config is kept as the newest backup.
+fn replace_config_file(config_path: &Path, contents: &str) -> std::io::Result<()> {
+    let temp_path = get_sibling_path(&format!(
+        "{}-{}.tmp",
+        std::process::id(),
+        SAVE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+
+    if config_path.exists() {
+        rotate_backups()?;
+        fs::copy(config_path, get_backup_path(1))?;
+    }
+    fs::rename(&temp_path, config_path)
+}
+
+// Whether the config matches what was last loaded from or saved to disk
+pub fn is_config_saved(config: &Config) -> bool {
+    let last_saved = LAST_SAVED.lock().unwrap();
+    config_json(config).ok() == *last_saved
+}
+
+pub fn mark_config_saved(config: &Config) {
+    *LAST_SAVED.lock().unwrap() = config_json(config).ok();
+}
+
+fn config_json(config: &Config) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(config)
+}
+
+// Uses a config file other than the default one, must be called before the config is loaded
+pub fn set_config_path(path: PathBuf) -> std::io::Result<()> {
+    let path = std::path::absolute(path)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    CONFIG_PATH
+        .set(path)
+        .map_err(|_| std::io::Error::other("config path is already set"))
 }
 
 pub fn get_config_path() -> PathBuf {
+    if let Some(path) = CONFIG_PATH.get() {
+        return path.clone();
+    }
+
     let mut path = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
     path.push("vrc-owo");
     fs::create_dir_all(&path).ok();
     path.push(CONFIG_FILE);
     path
 }
+
+// Keeps a copy of a config file that failed to load, so it survives the next save
+pub fn quarantine_config() -> std::io::Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let path = get_sibling_path(&format!("broken-{}", timestamp));
+    fs::copy(get_config_path(), &path)?;
+    Ok(path)
+}
+
+// Files that belong to the config are named after it, so several configs can share a directory
+fn get_sibling_path(suffix: &str) -> PathBuf {
+    let mut path = get_config_path().into_os_string();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn get_migration_backup_path(version: u32) -> PathBuf {
+    get_sibling_path(&format!("v{}.bak", version))
+}
+
+// Backup 1 is the newest, BACKUP_COUNT the oldest
+fn get_backup_path(index: usize) -> PathBuf {
+    get_sibling_path(&format!("{}.bak", index))
+}
+
+fn rotate_backups() -> std::io::Result<()> {
+    for index in (1..BACKUP_COUNT).rev() {
+        let path = get_backup_path(index);
+        if path.exists() {
+            fs::rename(path, get_backup_path(index + 1))?;
+        }
+    }
+    Ok(())
+}
+
+fn config_version(value: &Value) -> u32 {
+    value
+        .get("version")
+        .and_then(Value::as_u64)
+        .map(|version| version as u32)
+        .unwrap_or(0)
+}
+
+// Version 0 is the same format without the version field
+fn migrate_v0_to_v1(value: &mut Value) {
+    if let Some(object) = value.as_object_mut() {
+        object.insert("version".to_string(), Value::from(1));
+    }
+}
+
+// Version 1 had a single set of muscles and connection settings, which become the default profile
+fn migrate_v1_to_v2(value: &mut Value) {
+    let Some(object) = value.as_object_mut() else {
+        return;
+    };
+    let muscles = object.remove("muscles").unwrap_or(Value::Array(Vec::new()));
+    let ip_address = object.remove("ip_address").unwrap_or(Value::Null);
+
+    let mut profile = Map::new();
+    profile.insert("name".to_string(), Value::from(DEFAULT_PROFILE));
+    profile.insert("muscles".to_string(), muscles);
+    profile.insert("ip_address".to_string(), ip_address);
+
+    object.insert("active_profile".to_string(), Value::from(DEFAULT_PROFILE));
+    object.insert(
+        "profiles".to_string(),
+        Value::Array(vec![Value::Object(profile)]),
+    );
+    object.insert("version".to_string(), Value::from(2));
+}
+
+// Version 3 added avatar bindings, the previously active profile stays the default
+fn migrate_v2_to_v3(value: &mut Value) {
+    let Some(object) = value.as_object_mut() else {
+        return;
+    };
+    let active_profile = object
+        .get("active_profile")
+        .cloned()
+        .unwrap_or(Value::from(DEFAULT_PROFILE));
+
+    object.insert("default_profile".to_string(), active_profile);
+    object.insert("avatar_profiles".to_string(), Value::Object(Map::new()));
+    object.insert("version".to_string(), Value::from(3));
+}
+
+// Version 4 allows several prefabs per profile, the existing muscles keep the prefab's default prefix
+fn migrate_v3_to_v4(value: &mut Value) {
+    let Some(object) = value.as_object_mut() else {
+        return;
+    };
+    if let Some(profiles) = object.get_mut("profiles").and_then(Value::as_array_mut) {
+        for profile in profiles.iter_mut().filter_map(Value::as_object_mut) {
+            let muscles = profile
+                .remove("muscles")
+                .unwrap_or(Value::Array(Vec::new()));
+
+            let mut instance = Map::new();
+            instance.insert("prefix".to_string(), Value::from(DEFAULT_PREFIX));
+            instance.insert("muscles".to_string(), muscles);
+            profile.insert(
+                "instances".to_string(),
+                Value::Array(vec![Value::Object(instance)]),
+            );
+        }
+    }
+    object.insert("version".to_string(), Value::from(4));
+}
+
+// Version 5 links each parameter to any number of muscles with a weight. Parameters that were
+// missing from the file used to fall back to the defaults, so they are added.
+fn migrate_v4_to_v5(value: &mut Value) {
+    let Some(object) = value.as_object_mut() else {
+        return;
+    };
+    if let Some(profiles) = object.get_mut("profiles").and_then(Value::as_array_mut) {
+        for profile in profiles.iter_mut() {
+            if let Some(instances) = profile.get_mut("instances").and_then(Value::as_array_mut) {
+                instances
+                    .iter_mut()
+                    .for_each(migrate_instance_muscles_to_parameters);
+            }
+        }
+    }
+    object.insert("version".to_string(), Value::from(5));
+}
+
+// Shared with profile files, which contain instances in the same format
+pub(crate) fn migrate_instance_muscles_to_parameters(instance: &mut Value) {
+    let Some(instance) = instance.as_object_mut() else {
+        return;
+    };
+    let muscles = instance
+        .remove("muscles")
+        .unwrap_or(Value::Array(Vec::new()));
+    let mut parameters = Vec::new();
+    for mut muscle in muscles.as_array().cloned().unwrap_or_default() {
+        let Some(muscle) = muscle.as_object_mut() else {
+            continue;
+        };
+        let mut link = Map::new();
+        link.insert(
+            "muscle".to_string(),
+            muscle.remove("muscle").unwrap_or(Value::Null),
+        );
+        link.insert("weight".to_string(), Value::from(1.0));
+        muscle.insert(
+            "muscles".to_string(),
+            Value::Array(vec![Value::Object(link)]),
+        );
+        parameters.push(Value::Object(muscle.clone()));
+    }
+
+    let defaults = InstanceConfig::from_mapping("", &default_muscle_mapping()).parameters;
+    for default in defaults {
+        let exists = parameters
+            .iter()
+            .any(|parameter| parameter.get("name").and_then(Value::as_str) == Some(&default.name));
+        if !exists && let Ok(default) = serde_json::to_value(default) {
+            parameters.push(default);
+        }
+    }
+    instance.insert("parameters".to_string(), Value::Array(parameters));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As written before the config had a version
+    const V0_CONFIG: &str = r#"{
+        "muscles": [
+            {
+                "name": "Pectoral_R",
+                "muscle": "PectoralR",
+                "intensity_touch": 33,
+                "intensity_impact": 44,
+                "intensity_stab": 55
+            }
+        ],
+        "ip_address": "192.168.1.20"
+    }"#;
+
+    fn migrate(contents: &str) -> Config {
+        let mut value: Value = serde_json::from_str(contents).unwrap();
+        for migration in &MIGRATIONS[config_version(&value) as usize..] {
+            migration(&mut value);
+        }
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn migrates_v0_to_the_current_version() {
+        let config = migrate(V0_CONFIG);
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.active_profile, DEFAULT_PROFILE);
+        assert_eq!(config.default_profile, DEFAULT_PROFILE);
+        assert!(config.avatar_profiles.is_empty());
+
+        let profile = config.profile(DEFAULT_PROFILE).unwrap();
+        assert_eq!(profile.ip_address.as_deref(), Some("192.168.1.20"));
+        let [instance] = profile.instances.as_slice() else {
+            panic!("expected one instance, found {}", profile.instances.len());
+        };
+        assert_eq!(instance.prefix, DEFAULT_PREFIX);
+        let parameter = &instance.parameters[0];
+        assert_eq!(
+            (
+                parameter.name.as_str(),
+                parameter.intensity_touch,
+                parameter.intensity_impact,
+                parameter.intensity_stab
+            ),
+            ("Pectoral_R", 33, 44, 55)
+        );
+        let [link] = parameter.muscles.as_slice() else {
+            panic!("expected one link, found {}", parameter.muscles.len());
+        };
+        assert_eq!((link.muscle.as_str(), link.weight), ("PectoralR", 1.0));
+
+        // Parameters missing from the file get their defaults
+        let defaults = default_muscle_mapping().parameters;
+        assert_eq!(instance.parameters.len(), defaults.len());
+        for default in defaults {
+            assert!(instance
+                .parameters
+                .iter()
+                .any(|parameter| parameter.name == default.name));
+        }
+    }
+
+    #[test]
+    fn migrated_config_round_trips() {
+        let json = serde_json::to_string_pretty(&migrate(V0_CONFIG)).unwrap();
+        let reloaded = serde_json::to_string_pretty(&migrate(&json)).unwrap();
+        assert_eq!(reloaded, json);
+    }
+
+    fn config_with_profiles() -> Config {
+        let mut config = Config::default();
+        config.create_profile("Soft").unwrap();
+        config.create_profile("Strong").unwrap();
+        config.set_active_profile("Soft").unwrap();
+        config.set_default_profile("Soft").unwrap();
+        config.bind_avatar("avtr_soft", "Soft").unwrap();
+        config.bind_avatar("avtr_strong", "Strong").unwrap();
+        config
+    }
+
+    #[test]
+    fn rename_follows_every_reference() {
+        let mut config = config_with_profiles();
+        config.set_profile_override("Strong").unwrap();
+        config.rename_profile("Soft", "Gentle").unwrap();
+        assert_eq!(config.active_profile, "Gentle");
+        assert_eq!(config.default_profile, "Gentle");
+        assert_eq!(config.profile_for_avatar("avtr_soft"), "Gentle");
+        assert_eq!(config.profile_for_avatar("avtr_strong"), "Strong");
+        assert!(config.profile("Soft").is_none());
+
+        config.rename_profile("Strong", "Hard").unwrap();
+        assert_eq!(config.active_profile, "Gentle");
+        assert_eq!(config.profile_for_avatar("avtr_strong"), "Hard");
+        assert_eq!(config.profile_override.as_deref(), Some("Hard"));
+        assert_eq!(config.active_profile_name(), "Hard");
+        assert!(matches!(
+            config.rename_profile("Hard", "Gentle"),
+            Err(ConfigError::DuplicateProfile(_))
+        ));
+    }
+
+    #[test]
+    fn delete_clears_every_reference() {
+        let mut config = config_with_profiles();
+        config.set_profile_override("Strong").unwrap();
+        config.delete_profile("Strong").unwrap();
+        assert_eq!(config.active_profile, "Soft");
+        assert_eq!(config.profile_override, None);
+        assert_eq!(config.active_profile_name(), "Soft");
+        assert!(!config.avatar_profiles.contains_key("avtr_strong"));
+        assert_eq!(config.profile_for_avatar("avtr_soft"), "Soft");
+
+        config.delete_profile("Soft").unwrap();
+        assert_eq!(config.default_profile, DEFAULT_PROFILE);
+        assert_eq!(config.active_profile, DEFAULT_PROFILE);
+        assert!(config.avatar_profiles.is_empty());
+        assert!(matches!(
+            config.delete_profile(DEFAULT_PROFILE),
+            Err(ConfigError::LastProfile)
+        ));
+    }
+
+    fn config_with_ip(n: u8) -> Config {
+        let mut config = Config::default();
+        config.active_profile_mut().ip_address = Some(format!("192.168.1.{}", n));
+        config
+    }
+
+    fn ip_at(path: &Path) -> String {
+        let (config, _) = parse_config(&fs::read_to_string(path).unwrap()).unwrap();
+        config.active_profile().ip_address.clone().unwrap()
+    }
+
+    // The config path can only be set once, so saving and restoring share a test
+    #[test]
+    fn saves_keep_backups_and_restores_step_back() {
+        let dir = std::env::temp_dir().join(format!("vrc-owo-{}-backups", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        set_config_path(dir.join(CONFIG_FILE)).unwrap();
+        let config_path = get_config_path();
+
+        for n in 1..=BACKUP_COUNT as u8 + 2 {
+            save_config(&config_with_ip(n)).unwrap();
+        }
+        assert_eq!(ip_at(&config_path), "192.168.1.7");
+        assert_eq!(ip_at(&get_backup_path(1)), "192.168.1.6");
+        assert_eq!(ip_at(&get_backup_path(BACKUP_COUNT)), "192.168.1.2");
+        assert!(!get_backup_path(BACKUP_COUNT + 1).exists());
+
+        // Saving the config on disk again keeps the backups as they are
+        save_config(&config_with_ip(7)).unwrap();
+        assert_eq!(ip_at(&get_backup_path(1)), "192.168.1.6");
+
+        let restored = restore_previous_config().unwrap();
+        assert_eq!(
+            restored.active_profile().ip_address.as_deref(),
+            Some("192.168.1.6")
+        );
+        assert!(is_config_saved(&restored));
+        assert_eq!(ip_at(&config_path), "192.168.1.6");
+        assert_eq!(ip_at(&get_backup_path(1)), "192.168.1.7");
+        assert_eq!(ip_at(&get_backup_path(2)), "192.168.1.5");
+
+        restore_previous_config().unwrap();
+        assert_eq!(ip_at(&config_path), "192.168.1.5");
+        assert_eq!(ip_at(&get_backup_path(1)), "192.168.1.6");
+        assert_eq!(ip_at(&get_backup_path(2)), "192.168.1.7");
+        assert_eq!(ip_at(&get_backup_path(3)), "192.168.1.4");
+        assert_eq!(ip_at(&get_backup_path(BACKUP_COUNT)), "192.168.1.2");
+
+        // A save starts over from the newest backup
+        save_config(&config_with_ip(8)).unwrap();
+        restore_previous_config().unwrap();
+        assert_eq!(ip_at(&config_path), "192.168.1.5");
+        assert_eq!(ip_at(&get_backup_path(1)), "192.168.1.8");
+
+        let files = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).ok();
+        assert_eq!(files, BACKUP_COUNT + 1);
+    }
+}
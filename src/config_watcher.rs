@@ -0,0 +1,107 @@
+use crate::config::{
+    apply_profile, get_config_path, is_config_saved, mark_config_saved, parse_config, Config,
+};
+use crate::config_writer::discard_pending_saves;
+use crate::muscle::InstanceMappings;
+use crate::owo_thread::Connection;
+use notify::{RecursiveMode, Watcher};
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Editors often write a file in several steps, wait for them to finish before reloading
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+pub fn start_config_watcher(
+    config: Arc<Mutex<Config>>,
+    muscle_mappings: Arc<Mutex<Vec<InstanceMappings>>>,
+    connection: Arc<Mutex<Connection>>,
+    config_warning: Arc<Mutex<Option<String>>>,
+) -> notify::Result<()> {
+    let config_path = get_config_path();
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+
+    // Watch the directory, editors that save by renaming replace the watched file
+    let config_dir = config_path.parent().unwrap_or(Path::new("."));
+    watcher.watch(config_dir, RecursiveMode::NonRecursive)?;
+
+    thread::spawn(move || {
+        // Keep the watcher alive for as long as the thread runs
+        let _watcher = watcher;
+
+        while let Ok(event) = receiver.recv() {
+            let touches_config = match event {
+                Ok(event) => !event.kind.is_access() && event.paths.contains(&config_path),
+                Err(e) => {
+                    println!("Error watching config file: {}", e);
+                    false
+                }
+            };
+            if !touches_config {
+                continue;
+            }
+
+            thread::sleep(SETTLE_TIME);
+            while receiver.try_recv().is_ok() {}
+
+            if let Some(warning) =
+                reload_config(&config_path, &config, &muscle_mappings, &connection)
+            {
+                println!("{}", warning);
+                *config_warning.lock().unwrap() = Some(warning);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Returns a warning for the user if the file could not be applied or replaced unsaved edits
+fn reload_config(
+    config_path: &Path,
+    config: &Mutex<Config>,
+    muscle_mappings: &Mutex<Vec<InstanceMappings>>,
+    connection: &Mutex<Connection>,
+) -> Option<String> {
+    // The file may be missing for a moment while an editor replaces it
+    let contents = fs::read_to_string(config_path).ok()?;
+    let mut new_config = match parse_config(&contents) {
+        Ok((new_config, _)) => new_config,
+        Err(e) => {
+            return Some(format!(
+                "Config file changed on disk but could not be loaded: {}. Keeping current settings.",
+                e
+            ));
+        }
+    };
+
+    // Our own saves and changes that only affect formatting need no reload
+    if is_config_saved(&new_config) {
+        return None;
+    }
+
+    let mut config = config.lock().unwrap();
+    // Edits from the window that are not on disk yet lose against the newer file
+    let had_unsaved_changes = !is_config_saved(&config);
+
+    discard_pending_saves();
+    new_config.revision = config.revision + 1;
+    // The command line profile lasts for the session, as long as it still exists
+    new_config.profile_override = config
+        .profile_override
+        .take()
+        .filter(|name| new_config.profile(name).is_some());
+    *config = new_config;
+    mark_config_saved(&config);
+    apply_profile(config.active_profile(), muscle_mappings, connection);
+    println!("Reloaded config file after it changed on disk");
+
+    had_unsaved_changes.then(|| {
+        "Config file changed on disk, unsaved changes made in this window were discarded."
+            .to_string()
+    })
+}
@@ -0,0 +1,86 @@
+use crate::config::{save_config, Config};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+// Saves requested within this time of each other are written once
+pub const SAVE_DELAY: Duration = Duration::from_millis(1000);
+
+static WRITER: OnceLock<Sender<WriterMessage>> = OnceLock::new();
+
+enum WriterMessage {
+    Save(Box<Config>),
+    Flush(Sender<()>),
+    Discard,
+}
+
+// Queues the config to be written by the background writer
+pub fn request_save(config: &Config) {
+    if writer()
+        .send(WriterMessage::Save(Box::new(config.clone())))
+        .is_err()
+    {
+        println!("Config writer stopped, saving directly");
+        write(config);
+    }
+}
+
+// Writes any queued config immediately and waits for it to finish
+pub fn flush_saves() {
+    let (done_sender, done_receiver) = mpsc::channel();
+    if writer().send(WriterMessage::Flush(done_sender)).is_ok() {
+        done_receiver.recv().ok();
+    }
+}
+
+// Drops queued configs, used when the file on disk replaced the config in memory
+pub fn discard_pending_saves() {
+    writer().send(WriterMessage::Discard).ok();
+}
+
+fn writer() -> &'static Sender<WriterMessage> {
+    WRITER.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut pending: Option<Box<Config>> = None;
+            loop {
+                let message = if pending.is_some() {
+                    match receiver.recv_timeout(SAVE_DELAY) {
+                        Ok(message) => Some(message),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                } else {
+                    match receiver.recv() {
+                        Ok(message) => Some(message),
+                        Err(_) => break,
+                    }
+                };
+
+                match message {
+                    Some(WriterMessage::Save(config)) => pending = Some(config),
+                    Some(WriterMessage::Flush(done)) => {
+                        if let Some(config) = pending.take() {
+                            write(&config);
+                        }
+                        done.send(()).ok();
+                    }
+                    Some(WriterMessage::Discard) => pending = None,
+                    None => {
+                        if let Some(config) = pending.take() {
+                            write(&config);
+                        }
+                    }
+                }
+            }
+        });
+        sender
+    })
+}
+
+fn write(config: &Config) {
+    if let Err(e) = save_config(config) {
+        println!("Error saving config: {}", e);
+    }
+}
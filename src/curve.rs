@@ -0,0 +1,155 @@
+use crate::muscle::InteractionType;
+use serde::{Deserialize, Serialize};
+
+// Maps a contact input (depth, or velocity relative to the fastest contact) to the fraction of
+// the configured intensity that is sent
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseCurve {
+    #[default]
+    Linear,
+    // Values above 1 soften light contacts, values below 1 make them stronger
+    Gamma {
+        gamma: f32,
+    },
+    Smoothstep,
+    // Points as [input, output], linear in between and flat outside
+    Piecewise {
+        points: Vec<[f32; 2]>,
+    },
+}
+
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct ResponseCurves {
+    #[serde(default)]
+    pub touch: ResponseCurve,
+    #[serde(default)]
+    pub impact: ResponseCurve,
+    #[serde(default)]
+    pub stab: ResponseCurve,
+    #[serde(default)]
+    pub projectile: ResponseCurve,
+    #[serde(default)]
+    pub grab: ResponseCurve,
+}
+
+impl ResponseCurve {
+    pub fn evaluate(&self, input: f32) -> f32 {
+        match self {
+            // Not clamped, so fast impacts keep scaling like they always did
+            ResponseCurve::Linear => input,
+            ResponseCurve::Gamma { gamma } => input.max(0.0).powf(*gamma),
+            ResponseCurve::Smoothstep => {
+                let t = input.clamp(0.0, 1.0);
+                t * t * (3.0 - 2.0 * t)
+            }
+            ResponseCurve::Piecewise { points } => {
+                let (Some(first), Some(last)) = (points.first(), points.last()) else {
+                    return input;
+                };
+                if input <= first[0] {
+                    return first[1];
+                }
+                points
+                    .windows(2)
+                    .find(|pair| input <= pair[1][0])
+                    .map(|pair| {
+                        let [x0, y0] = pair[0];
+                        let [x1, y1] = pair[1];
+                        y0 + (y1 - y0) * (input - x0) / (x1 - x0)
+                    })
+                    .unwrap_or(last[1])
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            ResponseCurve::Linear | ResponseCurve::Smoothstep => Ok(()),
+            ResponseCurve::Gamma { gamma } => {
+                if gamma.is_finite() && *gamma > 0.0 {
+                    Ok(())
+                } else {
+                    Err(format!("gamma must be above 0, found {}", gamma))
+                }
+            }
+            ResponseCurve::Piecewise { points } => {
+                if points.len() < 2 {
+                    return Err("at least 2 points are needed".to_string());
+                }
+                for point in points {
+                    if !(0.0..=1.0).contains(&point[0]) || !(0.0..=1.0).contains(&point[1]) {
+                        return Err(format!(
+                            "point {}, {} is outside of 0 to 1",
+                            point[0], point[1]
+                        ));
+                    }
+                }
+                if points.windows(2).any(|pair| pair[0][0] >= pair[1][0]) {
+                    return Err("point inputs must be increasing".to_string());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ResponseCurves {
+    pub fn get(&self, interaction_type: InteractionType) -> &ResponseCurve {
+        match interaction_type {
+            InteractionType::Touch => &self.touch,
+            InteractionType::Impact => &self.impact,
+            InteractionType::Stab => &self.stab,
+            InteractionType::Projectile => &self.projectile,
+            InteractionType::Grab => &self.grab,
+        }
+    }
+
+    pub fn get_mut(&mut self, interaction_type: InteractionType) -> &mut ResponseCurve {
+        match interaction_type {
+            InteractionType::Touch => &mut self.touch,
+            InteractionType::Impact => &mut self.impact,
+            InteractionType::Stab => &mut self.stab,
+            InteractionType::Projectile => &mut self.projectile,
+            InteractionType::Grab => &mut self.grab,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curves_reach_their_endpoints() {
+        let curves = [
+            ResponseCurve::Linear,
+            ResponseCurve::Gamma { gamma: 2.2 },
+            ResponseCurve::Gamma { gamma: 0.5 },
+            ResponseCurve::Smoothstep,
+            ResponseCurve::Piecewise {
+                points: vec![[0.0, 0.0], [0.5, 0.8], [1.0, 1.0]],
+            },
+        ];
+        for curve in curves {
+            assert_eq!(curve.evaluate(0.0), 0.0, "{:?}", curve);
+            assert_eq!(curve.evaluate(1.0), 1.0, "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn piecewise_is_flat_outside_its_points() {
+        let curve = ResponseCurve::Piecewise {
+            points: vec![[0.2, 0.1], [0.8, 0.9]],
+        };
+        assert_eq!(curve.evaluate(0.0), 0.1);
+        assert_eq!(curve.evaluate(1.0), 0.9);
+        assert!((curve.evaluate(0.5) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn only_linear_scales_past_full_input() {
+        assert_eq!(ResponseCurve::Linear.evaluate(2.0), 2.0);
+        assert_eq!(ResponseCurve::Smoothstep.evaluate(2.0), 1.0);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::muscle::InteractionType;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+// Longest attack, decay, hold or release that can be configured
+pub const MAX_ENVELOPE_MS: u32 = 10_000;
+
+// How the intensity of an interaction changes over time, as a fraction of its intensity. Impacts,
+// stabs and projectiles are instant, they play the whole envelope from the moment they are
+// received. Touches and grabs hold the sustain level while the contact lasts.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Envelope {
+    // Time to rise to the full intensity
+    pub attack_ms: u32,
+    // Time to fall from the full intensity to the sustain level
+    pub decay_ms: u32,
+    pub sustain: f32,
+    // Shortest time the sustain level is held
+    pub hold_ms: u32,
+    // Time to fall from the sustain level to nothing once the contact ends
+    pub release_ms: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Envelopes {
+    pub touch: Envelope,
+    pub impact: Envelope,
+    pub stab: Envelope,
+    pub projectile: Envelope,
+    pub grab: Envelope,
+}
+
+// An envelope that is playing on a contact
+#[derive(Clone)]
+pub struct EnvelopeState {
+    pub interaction_type: InteractionType,
+    pub depth: f32,
+    pub velocity: f32,
+    pub started: Instant,
+    pub released: Option<Instant>,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self {
+            attack_ms: 0,
+            decay_ms: 0,
+            sustain: 1.0,
+            hold_ms: 0,
+            release_ms: 0,
+        }
+    }
+}
+
+// Touches follow the contact, impacts spike and fade, stabs last a moment even for a short hit and
+// projectiles are a sharp hit that is over quickly. Grabs build up like a squeeze.
+impl Default for Envelopes {
+    fn default() -> Self {
+        Self {
+            touch: Envelope::default(),
+            impact: Envelope {
+                decay_ms: 400,
+                sustain: 0.0,
+                ..Envelope::default()
+            },
+            stab: Envelope {
+                hold_ms: 500,
+                release_ms: 250,
+                ..Envelope::default()
+            },
+            projectile: Envelope {
+                decay_ms: 150,
+                sustain: 0.0,
+                ..Envelope::default()
+            },
+            grab: Envelope {
+                attack_ms: 200,
+                release_ms: 200,
+                ..Envelope::default()
+            },
+        }
+    }
+}
+
+impl Envelope {
+    // Level after the given time, None once it has finished. The sustain level is held until the
+    // release, but at least until the attack, decay and hold are over.
+    pub fn level(&self, elapsed_ms: f32, released_ms: Option<f32>) -> Option<f32> {
+        let hold_until = (self.attack_ms + self.decay_ms + self.hold_ms) as f32;
+        let Some(release_start) = released_ms.map(|released| released.max(hold_until)) else {
+            return Some(self.held_level(elapsed_ms));
+        };
+        if elapsed_ms < release_start {
+            return Some(self.held_level(elapsed_ms));
+        }
+        let release = elapsed_ms - release_start;
+        if release >= self.release_ms as f32 {
+            return None;
+        }
+        Some(self.held_level(release_start) * (1.0 - release / self.release_ms as f32))
+    }
+
+    fn held_level(&self, elapsed_ms: f32) -> f32 {
+        let attack = self.attack_ms as f32;
+        let decay = self.decay_ms as f32;
+        if elapsed_ms < attack {
+            elapsed_ms / attack
+        } else if elapsed_ms < attack + decay {
+            1.0 - (1.0 - self.sustain) * (elapsed_ms - attack) / decay
+        } else {
+            self.sustain
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.sustain) {
+            return Err(format!(
+                "sustain must be between 0 and 1, found {}",
+                self.sustain
+            ));
+        }
+        for (name, value) in [
+            ("attack", self.attack_ms),
+            ("decay", self.decay_ms),
+            ("hold", self.hold_ms),
+            ("release", self.release_ms),
+        ] {
+            if value > MAX_ENVELOPE_MS {
+                return Err(format!(
+                    "{} is {} ms, longest allowed is {} ms",
+                    name, value, MAX_ENVELOPE_MS
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Envelopes {
+    pub fn get(&self, interaction_type: InteractionType) -> &Envelope {
+        match interaction_type {
+            InteractionType::Touch => &self.touch,
+            InteractionType::Impact => &self.impact,
+            InteractionType::Stab => &self.stab,
+            InteractionType::Projectile => &self.projectile,
+            InteractionType::Grab => &self.grab,
+        }
+    }
+
+    pub fn get_mut(&mut self, interaction_type: InteractionType) -> &mut Envelope {
+        match interaction_type {
+            InteractionType::Touch => &mut self.touch,
+            InteractionType::Impact => &mut self.impact,
+            InteractionType::Stab => &mut self.stab,
+            InteractionType::Projectile => &mut self.projectile,
+            InteractionType::Grab => &mut self.grab,
+        }
+    }
+}
+
+impl EnvelopeState {
+    pub fn new(interaction_type: InteractionType, depth: f32, velocity: f32, now: Instant) -> Self {
+        Self {
+            interaction_type,
+            depth,
+            velocity,
+            started: now,
+            released: None,
+        }
+    }
+
+    pub fn level(&self, envelopes: &Envelopes, now: Instant) -> Option<f32> {
+        let elapsed_ms = |time: Instant| time.duration_since(self.started).as_secs_f32() * 1000.0;
+        envelopes
+            .get(self.interaction_type)
+            .level(elapsed_ms(now), self.released.map(elapsed_ms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENVELOPE: Envelope = Envelope {
+        attack_ms: 100,
+        decay_ms: 100,
+        sustain: 0.5,
+        hold_ms: 100,
+        release_ms: 200,
+    };
+
+    fn assert_level(elapsed_ms: f32, released_ms: Option<f32>, expected: Option<f32>) {
+        let level = ENVELOPE.level(elapsed_ms, released_ms);
+        match (level, expected) {
+            (Some(level), Some(expected)) => assert!(
+                (level - expected).abs() < 1e-6,
+                "level at {} ms is {}, expected {}",
+                elapsed_ms,
+                level,
+                expected
+            ),
+            _ => assert_eq!(level, expected, "level at {} ms", elapsed_ms),
+        }
+    }
+
+    #[test]
+    fn rises_decays_and_sustains() {
+        assert_level(0.0, None, Some(0.0));
+        assert_level(50.0, None, Some(0.5));
+        assert_level(100.0, None, Some(1.0));
+        assert_level(150.0, None, Some(0.75));
+        assert_level(200.0, None, Some(0.5));
+        assert_level(5000.0, None, Some(0.5));
+    }
+
+    #[test]
+    fn releases_from_the_sustain_level() {
+        assert_level(500.0, Some(500.0), Some(0.5));
+        assert_level(600.0, Some(500.0), Some(0.25));
+        assert_level(700.0, Some(500.0), None);
+    }
+
+    #[test]
+    fn early_release_waits_for_the_hold() {
+        assert_level(250.0, Some(50.0), Some(0.5));
+        assert_level(400.0, Some(50.0), Some(0.25));
+        assert_level(500.0, Some(50.0), None);
+    }
+
+    #[test]
+    fn impacts_play_out_without_a_contact() {
+        let impact = Envelopes::default().impact;
+        assert_eq!(impact.level(0.0, Some(0.0)), Some(1.0));
+        assert_eq!(impact.level(200.0, Some(0.0)), Some(0.5));
+        assert_eq!(impact.level(400.0, Some(0.0)), None);
+    }
+}
@@ -0,0 +1,674 @@
+use crate::muscle::{
+    find_parameter, muscle_name, parse_muscle, InstanceMappings, MuscleState, MAX_INTENSITY,
+};
+use owo_skin::muscles::{Muscle, ALL};
+use owo_skin::sensation::Sensation;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Longest hold, interval, duration or cooldown that can be configured
+pub const MAX_GESTURE_MS: u32 = 20_000;
+// Contacts kept on a parameter until the recogniser sees them, in case it is not running
+const MAX_CONTACTS: usize = 16;
+
+// Contacts on several muscles that play their own sensation once they are recognised
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Gesture {
+    pub name: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    pub pattern: GesturePattern,
+    pub sensation: GestureSensation,
+    // Shortest time between two plays of the gesture
+    #[serde(default)]
+    pub cooldown_ms: u32,
+}
+
+// Muscles are named like in the muscle links of a parameter. Depths are of the contacts on the
+// parameters linked to the muscles.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GesturePattern {
+    // Every group has a muscle touched at least this deep, all at once for the hold time, like a
+    // hug on the chest and the back. Played once until one of the groups lets go.
+    Together {
+        groups: Vec<Vec<String>>,
+        min_depth: f32,
+        hold_ms: u32,
+    },
+    // Contacts starting on the muscles one after the other, like a pat on the back. Contacts closer
+    // than the shortest interval count as one, a longer pause starts over.
+    Taps {
+        muscles: Vec<String>,
+        count: u32,
+        min_interval_ms: u32,
+        max_interval_ms: u32,
+        min_depth: f32,
+    },
+    // A contact on the muscles that is over within the duration, like a poke with a finger
+    Poke {
+        muscles: Vec<String>,
+        max_duration_ms: u32,
+        min_depth: f32,
+    },
+}
+
+// Played on every muscle of the pattern
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct GestureSensation {
+    // From 1 to 100 Hz
+    pub frequency: u8,
+    pub duration_ms: u32,
+    pub intensity: u8,
+    // Time to fade in and to fade out
+    #[serde(default)]
+    pub ramp_ms: u32,
+}
+
+// A contact on a parameter, from its first depth to the depth of 0 that ends it
+#[derive(Clone, Copy, Debug)]
+pub struct ContactEvent {
+    pub started: Instant,
+    pub ended: Option<Instant>,
+    pub peak: f32,
+}
+
+// Runs on the OWO thread, it remembers what each gesture has seen so far
+#[derive(Default)]
+pub struct GestureRecognizer {
+    last_update: Option<Instant>,
+    trackers: HashMap<String, GestureTracker>,
+}
+
+#[derive(Default)]
+struct GestureTracker {
+    played_at: Option<Instant>,
+    held_since: Option<Instant>,
+    held_played: bool,
+    taps: Vec<Instant>,
+}
+
+impl Gesture {
+    // Every muscle the pattern looks at, in the order of the vest
+    pub fn muscles(&self) -> Vec<Muscle> {
+        let names: Vec<&String> = match &self.pattern {
+            GesturePattern::Together { groups, .. } => groups.iter().flatten().collect(),
+            GesturePattern::Taps { muscles, .. } | GesturePattern::Poke { muscles, .. } => {
+                muscles.iter().collect()
+            }
+        };
+        ALL.into_iter()
+            .filter(|muscle| names.contains(&&muscle_name(*muscle)))
+            .collect()
+    }
+
+    // Scaled like the contacts, but never above the maximum intensity of a parameter linked to
+    // the muscle
+    pub fn muscle_intensities(
+        &self,
+        master: f32,
+        instances: &[InstanceMappings],
+    ) -> Vec<(Muscle, u8)> {
+        let intensity = self.sensation.intensity as f32 * master;
+        self.muscles()
+            .into_iter()
+            .map(|muscle| {
+                let limit = instances
+                    .iter()
+                    .flat_map(|instance| &instance.mapping.parameters)
+                    .filter(|parameter| {
+                        parameter
+                            .links
+                            .iter()
+                            .any(|link| link.weight > 0.0 && link.muscle as u8 == muscle as u8)
+                    })
+                    .map(|parameter| parameter.limits.max_intensity)
+                    .min()
+                    .unwrap_or(MAX_INTENSITY);
+                (muscle, intensity.round().min(limit as f32) as u8)
+            })
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let (muscles, min_depth, times): (Vec<&String>, f32, Vec<(&str, u32)>) = match &self.pattern
+        {
+            GesturePattern::Together {
+                groups,
+                min_depth,
+                hold_ms,
+            } => {
+                if groups.is_empty() || groups.iter().any(Vec::is_empty) {
+                    return Err("every group needs at least one muscle".to_string());
+                }
+                (
+                    groups.iter().flatten().collect(),
+                    *min_depth,
+                    vec![("hold", *hold_ms)],
+                )
+            }
+            GesturePattern::Taps {
+                muscles,
+                count,
+                min_interval_ms,
+                max_interval_ms,
+                min_depth,
+            } => {
+                if *count == 0 {
+                    return Err("count must be at least 1".to_string());
+                }
+                if min_interval_ms > max_interval_ms {
+                    return Err(format!(
+                        "shortest interval {} ms is above the longest {} ms",
+                        min_interval_ms, max_interval_ms
+                    ));
+                }
+                (
+                    muscles.iter().collect(),
+                    *min_depth,
+                    vec![
+                        ("shortest interval", *min_interval_ms),
+                        ("longest interval", *max_interval_ms),
+                    ],
+                )
+            }
+            GesturePattern::Poke {
+                muscles,
+                max_duration_ms,
+                min_depth,
+            } => (
+                muscles.iter().collect(),
+                *min_depth,
+                vec![("duration", *max_duration_ms)],
+            ),
+        };
+
+        if muscles.is_empty() {
+            return Err("at least one muscle is needed".to_string());
+        }
+        if let Some(muscle) = muscles.iter().find(|muscle| parse_muscle(muscle).is_none()) {
+            return Err(format!("unknown muscle '{}'", muscle));
+        }
+        if !(0.0..=1.0).contains(&min_depth) {
+            return Err(format!(
+                "depth must be between 0 and 1, found {}",
+                min_depth
+            ));
+        }
+        for (name, value) in times.into_iter().chain([("cooldown", self.cooldown_ms)]) {
+            if value > MAX_GESTURE_MS {
+                return Err(format!(
+                    "{} is {} ms, longest allowed is {} ms",
+                    name, value, MAX_GESTURE_MS
+                ));
+            }
+        }
+        self.sensation.validate()
+    }
+}
+
+impl GestureSensation {
+    pub fn sensation(&self) -> Sensation {
+        let ramp = self.ramp_ms as f32 / 1000.0;
+        Sensation::micro_sensation(
+            self.frequency,
+            self.duration_ms as f32 / 1000.0,
+            100,
+            ramp,
+            ramp,
+            0f32,
+            "".to_string(),
+        )
+    }
+
+    // The OWO app plays from 100 ms to 20 s, with ramps of up to 2 s
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=100).contains(&self.frequency) {
+            return Err(format!(
+                "frequency must be between 1 and 100 Hz, found {}",
+                self.frequency
+            ));
+        }
+        if !(100..=20_000).contains(&self.duration_ms) {
+            return Err(format!(
+                "duration must be between 100 and 20000 ms, found {}",
+                self.duration_ms
+            ));
+        }
+        if self.ramp_ms > 2000 {
+            return Err(format!(
+                "ramp must be at most 2000 ms, found {}",
+                self.ramp_ms
+            ));
+        }
+        if self.intensity > 100 {
+            return Err(format!(
+                "intensity must be at most 100, found {}",
+                self.intensity
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ContactEvent {
+    // Called with every depth of a parameter, after it was smoothed
+    pub fn record(contacts: &mut Vec<ContactEvent>, depth: f32, time: Instant) {
+        let touching = depth > 0.0;
+        match contacts.last_mut() {
+            Some(contact) if contact.ended.is_none() => {
+                if touching {
+                    contact.peak = contact.peak.max(depth);
+                } else {
+                    contact.ended = Some(time);
+                }
+            }
+            _ if touching => contacts.push(ContactEvent {
+                started: time,
+                ended: None,
+                peak: depth,
+            }),
+            _ => {}
+        }
+        if contacts.len() > MAX_CONTACTS {
+            contacts.remove(0);
+        }
+    }
+}
+
+impl GestureRecognizer {
+    // Called once per tick, returns the gestures to play. Contacts that ended are taken off the
+    // states once they are seen.
+    pub fn update<'a>(
+        &mut self,
+        gestures: &'a [Gesture],
+        states: &mut HashMap<String, MuscleState>,
+        instances: &[InstanceMappings],
+        now: Instant,
+    ) -> Vec<&'a Gesture> {
+        let since = self.last_update.replace(now);
+        let is_new = |time: Instant| since.is_none_or(|since| time > since);
+
+        // Deepest contact on each muscle, and the contacts with the muscles they are on
+        let mut depths = [0.0f32; ALL.len()];
+        let mut contacts: Vec<(Vec<u8>, ContactEvent)> = Vec::new();
+        for (param, state) in states.iter_mut() {
+            let Some(parameter) = find_parameter(param, instances) else {
+                state.contacts.clear();
+                continue;
+            };
+            let muscles: Vec<u8> = parameter
+                .links
+                .iter()
+                .filter(|link| link.weight > 0.0)
+                .map(|link| link.muscle as u8)
+                .collect();
+            for muscle in &muscles {
+                let depth = &mut depths[*muscle as usize];
+                *depth = depth.max(state.depth);
+            }
+            contacts.extend(
+                state
+                    .contacts
+                    .iter()
+                    .map(|contact| (muscles.clone(), *contact)),
+            );
+            state.contacts.retain(|contact| contact.ended.is_none());
+        }
+
+        let mut played = Vec::new();
+        for gesture in gestures.iter().filter(|gesture| gesture.enabled) {
+            let tracker = self.trackers.entry(gesture.name.clone()).or_default();
+            let recognised = match &gesture.pattern {
+                GesturePattern::Together {
+                    groups,
+                    min_depth,
+                    hold_ms,
+                } => {
+                    let held = groups.iter().all(|group| {
+                        muscle_ids(group)
+                            .iter()
+                            .any(|muscle| depths[*muscle as usize] >= *min_depth)
+                    });
+                    if held {
+                        let held_since = *tracker.held_since.get_or_insert(now);
+                        let recognised =
+                            !tracker.held_played && now - held_since >= millis(*hold_ms);
+                        tracker.held_played |= recognised;
+                        recognised
+                    } else {
+                        tracker.held_since = None;
+                        tracker.held_played = false;
+                        false
+                    }
+                }
+                GesturePattern::Taps {
+                    muscles,
+                    count,
+                    min_interval_ms,
+                    max_interval_ms,
+                    min_depth,
+                } => {
+                    let muscles = muscle_ids(muscles);
+                    let mut starts: Vec<Instant> = contacts
+                        .iter()
+                        .filter(|(on, contact)| {
+                            touches(on, &muscles)
+                                && contact.peak >= *min_depth
+                                && is_new(contact.started)
+                        })
+                        .map(|(_, contact)| contact.started)
+                        .collect();
+                    starts.sort();
+
+                    let mut recognised = false;
+                    for start in starts {
+                        if let Some(last) = tracker.taps.last() {
+                            let interval = start - *last;
+                            if interval < millis(*min_interval_ms) {
+                                continue;
+                            }
+                            if interval > millis(*max_interval_ms) {
+                                tracker.taps.clear();
+                            }
+                        }
+                        tracker.taps.push(start);
+                        if tracker.taps.len() >= *count as usize {
+                            tracker.taps.clear();
+                            recognised = true;
+                        }
+                    }
+                    recognised
+                }
+                GesturePattern::Poke {
+                    muscles,
+                    max_duration_ms,
+                    min_depth,
+                } => {
+                    let muscles = muscle_ids(muscles);
+                    contacts.iter().any(|(on, contact)| {
+                        touches(on, &muscles)
+                            && contact.peak >= *min_depth
+                            && contact.ended.is_some_and(|ended| {
+                                ended - contact.started <= millis(*max_duration_ms)
+                            })
+                    })
+                }
+            };
+
+            let cooled_down = tracker
+                .played_at
+                .is_none_or(|played_at| now - played_at >= millis(gesture.cooldown_ms));
+            if recognised && cooled_down {
+                tracker.played_at = Some(now);
+                played.push(gesture);
+            }
+        }
+        played
+    }
+}
+
+// A hug, a pat on the back and a poke on the front. They are turned off until the user picks them,
+// so configs from before gestures existed feel the same.
+pub fn default_gestures() -> Vec<Gesture> {
+    let names = |muscles: &[Muscle]| muscles.iter().map(|muscle| muscle_name(*muscle)).collect();
+    let front = [
+        Muscle::PectoralR,
+        Muscle::PectoralL,
+        Muscle::AbdominalR,
+        Muscle::AbdominalL,
+    ];
+    let back = [
+        Muscle::DorsalR,
+        Muscle::DorsalL,
+        Muscle::LumbarR,
+        Muscle::LumbarL,
+    ];
+    vec![
+        Gesture {
+            name: "Hug".to_string(),
+            enabled: false,
+            pattern: GesturePattern::Together {
+                groups: vec![names(&front), names(&back)],
+                min_depth: 0.2,
+                hold_ms: 300,
+            },
+            sensation: GestureSensation {
+                frequency: 20,
+                duration_ms: 1500,
+                intensity: 50,
+                ramp_ms: 500,
+            },
+            cooldown_ms: 3000,
+        },
+        Gesture {
+            name: "Back pat".to_string(),
+            enabled: false,
+            pattern: GesturePattern::Taps {
+                muscles: names(&[Muscle::DorsalR, Muscle::DorsalL]),
+                count: 3,
+                min_interval_ms: 150,
+                max_interval_ms: 800,
+                min_depth: 0.1,
+            },
+            sensation: GestureSensation {
+                frequency: 60,
+                duration_ms: 300,
+                intensity: 50,
+                ramp_ms: 0,
+            },
+            cooldown_ms: 1000,
+        },
+        Gesture {
+            name: "Poke".to_string(),
+            enabled: false,
+            pattern: GesturePattern::Poke {
+                muscles: names(&front),
+                max_duration_ms: 300,
+                min_depth: 0.1,
+            },
+            sensation: GestureSensation {
+                frequency: 100,
+                duration_ms: 100,
+                intensity: 40,
+                ramp_ms: 0,
+            },
+            cooldown_ms: 500,
+        },
+    ]
+}
+
+fn muscle_ids(names: &[String]) -> Vec<u8> {
+    names
+        .iter()
+        .filter_map(|name| parse_muscle(name))
+        .map(|muscle| muscle as u8)
+        .collect()
+}
+
+fn touches(on: &[u8], muscles: &[u8]) -> bool {
+    on.iter().any(|muscle| muscles.contains(muscle))
+}
+
+fn millis(ms: u32) -> Duration {
+    Duration::from_millis(ms as u64)
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::muscle::{
+        default_muscle_mapping, DepthVelocityRange, VelocityRange, VelocitySource,
+    };
+    use crate::smoothing::Smoothing;
+    use owo_skin::muscles::MuscleWithIntensity;
+
+    struct Session {
+        recognizer: GestureRecognizer,
+        gestures: Vec<Gesture>,
+        states: HashMap<String, MuscleState>,
+        instances: Vec<InstanceMappings>,
+        start: Instant,
+    }
+
+    impl Session {
+        fn new(gesture: &str) -> Self {
+            let gestures = default_gestures()
+                .into_iter()
+                .map(|gesture_config| Gesture {
+                    enabled: gesture_config.name == gesture,
+                    ..gesture_config
+                })
+                .collect();
+            Self {
+                recognizer: GestureRecognizer::default(),
+                gestures,
+                states: HashMap::new(),
+                instances: vec![InstanceMappings {
+                    prefix: "owo_pro".to_string(),
+                    mapping: default_muscle_mapping(),
+                    velocity_source: VelocitySource::default(),
+                    velocity_range: VelocityRange::default(),
+                    depth_velocity_range: DepthVelocityRange::default(),
+                }],
+                start: Instant::now(),
+            }
+        }
+
+        fn touch(&mut self, parameter: &str, depth: f32, ms: u64) {
+            let time = self.at(ms);
+            self.states
+                .entry(format!("owo_pro/{}", parameter))
+                .or_default()
+                .record_depth(depth, &Smoothing::default(), time);
+        }
+
+        fn tap(&mut self, parameter: &str, ms: u64) {
+            self.touch(parameter, 0.5, ms);
+            self.touch(parameter, 0.0, ms + 100);
+        }
+
+        fn tick(&mut self, ms: u64) -> Vec<String> {
+            let now = self.at(ms);
+            self.recognizer
+                .update(&self.gestures, &mut self.states, &self.instances, now)
+                .into_iter()
+                .map(|gesture| gesture.name.clone())
+                .collect()
+        }
+
+        fn at(&self, ms: u64) -> Instant {
+            self.start + Duration::from_millis(ms)
+        }
+    }
+
+    #[test]
+    fn default_gestures_are_off() {
+        let mut session = Session::new("");
+        session.tap("Pectoral_R", 0);
+        session.touch("Dorsal_L", 0.5, 0);
+        session.touch("Pectoral_L", 0.5, 0);
+        assert!(session.tick(0).is_empty());
+        assert!(session.tick(500).is_empty());
+    }
+
+    #[test]
+    fn hug_plays_once_held_and_after_the_cooldown() {
+        let mut session = Session::new("Hug");
+        session.touch("Pectoral_R", 0.5, 0);
+        session.touch("Dorsal_L", 0.5, 0);
+        assert!(session.tick(0).is_empty());
+        assert!(session.tick(250).is_empty());
+        assert_eq!(session.tick(300), ["Hug"]);
+        assert!(session.tick(550).is_empty());
+
+        // Letting go and hugging again within the cooldown is not played
+        session.touch("Dorsal_L", 0.0, 600);
+        assert!(session.tick(600).is_empty());
+        session.touch("Dorsal_L", 0.5, 700);
+        assert!(session.tick(700).is_empty());
+        assert!(session.tick(1000).is_empty());
+
+        session.touch("Dorsal_L", 0.0, 3400);
+        assert!(session.tick(3400).is_empty());
+        session.touch("Dorsal_L", 0.5, 3500);
+        assert!(session.tick(3500).is_empty());
+        assert_eq!(session.tick(3800), ["Hug"]);
+    }
+
+    #[test]
+    fn back_pat_needs_taps_in_rhythm_and_the_cooldown() {
+        let mut session = Session::new("Back pat");
+        session.tap("Dorsal_R", 0);
+        assert!(session.tick(100).is_empty());
+        session.tap("Dorsal_L", 300);
+        assert!(session.tick(400).is_empty());
+        session.tap("Dorsal_R", 600);
+        assert_eq!(session.tick(700), ["Back pat"]);
+
+        for ms in [900, 1100, 1300] {
+            session.tap("Dorsal_R", ms);
+            assert!(session.tick(ms + 100).is_empty());
+        }
+
+        for ms in [2000, 2300] {
+            session.tap("Dorsal_R", ms);
+            assert!(session.tick(ms + 100).is_empty());
+        }
+        session.tap("Dorsal_R", 2600);
+        assert_eq!(session.tick(2700), ["Back pat"]);
+
+        // Too slow to be a pat
+        for ms in [5000, 6000, 7000] {
+            session.tap("Dorsal_R", ms);
+            assert!(session.tick(ms + 100).is_empty());
+        }
+    }
+
+    #[test]
+    fn poke_is_short_and_waits_for_the_cooldown() {
+        let mut session = Session::new("Poke");
+        session.tap("Pectoral_L", 0);
+        assert_eq!(session.tick(100), ["Poke"]);
+
+        session.tap("Pectoral_L", 200);
+        assert!(session.tick(300).is_empty());
+        session.tap("Abdominal_R", 700);
+        assert_eq!(session.tick(800), ["Poke"]);
+
+        // A touch that lasts is not a poke, neither is one on the back
+        session.touch("Pectoral_R", 0.5, 1500);
+        assert!(session.tick(1500).is_empty());
+        session.touch("Pectoral_R", 0.0, 2000);
+        assert!(session.tick(2000).is_empty());
+        session.tap("Dorsal_R", 2500);
+        assert!(session.tick(2600).is_empty());
+    }
+
+    #[test]
+    fn gesture_intensity_stays_below_the_parameter_maximum() {
+        let mut session = Session::new("Hug");
+        for parameter in &mut session.instances[0].mapping.parameters {
+            if parameter
+                .links
+                .iter()
+                .any(|link| link.muscle as u8 == Muscle::PectoralR as u8)
+            {
+                parameter.limits.max_intensity = 10;
+            }
+        }
+        let hug = &session.gestures[0];
+        let packets: Vec<String> = hug
+            .muscle_intensities(0.5, &session.instances)
+            .into_iter()
+            .map(|(muscle, intensity)| MuscleWithIntensity::new(muscle, intensity).to_packet())
+            .collect();
+        assert!(packets.contains(&"0%10".to_string()), "{:?}", packets);
+        assert!(packets.contains(&"1%25".to_string()), "{:?}", packets);
+    }
+}
@@ -0,0 +1,71 @@
+use crate::config::Config;
+use crate::owo_thread::Connection;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(60);
+
+// Runs until the process is asked to stop, logging a status line every STATUS_INTERVAL
+pub async fn run_headless(
+    config: Arc<Mutex<Config>>,
+    connection: Arc<Mutex<Connection>>,
+    active_avatar: Arc<Mutex<Option<String>>>,
+) -> std::io::Result<()> {
+    println!("Running without a window, press Ctrl+C to stop");
+
+    let mut status_interval = tokio::time::interval(STATUS_INTERVAL);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            result = &mut shutdown => {
+                println!("Shutting down");
+                return result;
+            }
+            _ = status_interval.tick() => {
+                log_status(&config, &connection, &active_avatar);
+            }
+        }
+    }
+}
+
+fn log_status(
+    config: &Mutex<Config>,
+    connection: &Mutex<Connection>,
+    active_avatar: &Mutex<Option<String>>,
+) {
+    let profile = config.lock().unwrap().active_profile_name().to_string();
+    let avatar = active_avatar
+        .lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| "unknown".to_string());
+    let connection = {
+        let connection = connection.lock().unwrap();
+        match (&connection.ip_address, connection.needs_connect) {
+            (_, true) => "connecting".to_string(),
+            (Some(ip), false) => format!("connected to {}", ip),
+            (None, false) => "connected".to_string(),
+        }
+    };
+    println!(
+        "Status: OWO {}, profile '{}', avatar {}",
+        connection, profile, avatar
+    );
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
@@ -1,6 +1,16 @@
 // Export modules
+pub mod cli;
 pub mod config;
+pub mod config_watcher;
+pub mod config_writer;
+pub mod curve;
+pub mod envelope;
+pub mod gesture;
+pub mod headless;
 pub mod muscle;
 pub mod osc;
 pub mod owo_thread;
+pub mod profile_file;
+pub mod smoothing;
+#[cfg(feature = "gui")]
 pub mod ui;
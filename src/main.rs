@@ -1,38 +1,94 @@
+use clap::Parser;
 use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::process::ExitCode;
 use std::sync::{Arc, Mutex};
-use owo_skin::muscles::Muscle;
-use vrc_owo::config::load_config;
-use vrc_owo::muscle::{default_muscle_mappings, get_supported_parameters, MuscleState};
+use vrc_owo::cli::{Cli, Command};
+use vrc_owo::config::{
+    apply_profile, get_config_path, load_config, quarantine_config, read_config, save_config,
+    set_config_path, ConfigError,
+};
+use vrc_owo::config_watcher::start_config_watcher;
+use vrc_owo::config_writer::flush_saves;
+use vrc_owo::headless::run_headless;
+use vrc_owo::muscle::{get_supported_parameters, MuscleState};
 use vrc_owo::osc::setup_osc_listener;
-use vrc_owo::owo_thread::start_owo_thread;
+use vrc_owo::owo_thread::{start_owo_thread, Connection};
+use vrc_owo::profile_file::{export_profile, import_profile};
+#[cfg(feature = "gui")]
 use vrc_owo::ui::setup_ui;
 
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
-    // Initialize muscle mappings from config or defaults
-    let muscle_mappings: Arc<Mutex<[(&str, Muscle, u8, u8, u8); 10]>> =
-        Arc::new(Mutex::new(if let Some(config) = load_config() {
-            let mut mappings = default_muscle_mappings();
-            for muscle_config in config.muscles {
-                if let Some((_, _, intensity_touch, intensity_impact, intensity_stab)) = mappings
-                    .iter_mut()
-                    .find(|(name, _, _, _, _)| *name == muscle_config.name)
-                {
-                    *intensity_touch = muscle_config.intensity_touch;
-                    *intensity_impact = muscle_config.intensity_impact;
-                    *intensity_stab = muscle_config.intensity_stab;
-                }
-            }
-            mappings
-        } else {
-            default_muscle_mappings()
-        }));
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if let Some(path) = cli.config.clone()
+        && let Err(e) = set_config_path(path)
+    {
+        println!("Error using config file: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    match cli.command {
+        Some(Command::PrintConfig) => print_config(),
+        Some(Command::ValidateConfig) => validate_config(),
+        Some(Command::ExportProfile { path }) => export_profile_file(cli.profile.as_deref(), &path),
+        Some(Command::ImportProfile { path }) => import_profile_file(&path),
+        None => run(cli).await,
+    }
+}
+
+async fn run(cli: Cli) -> ExitCode {
+    let config_warning = Arc::new(Mutex::new(None::<String>));
+    let mut config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            let warning = match e {
+                ConfigError::Io(_) => format!("{}. Using default settings.", e),
+                _ => match quarantine_config() {
+                    Ok(path) => format!(
+                        "{}. Using default settings, your file was copied to {}",
+                        e,
+                        path.display()
+                    ),
+                    Err(quarantine_error) => {
+                        println!("Error copying broken config file: {}", quarantine_error);
+                        format!("{}. Using default settings.", e)
+                    }
+                },
+            };
+            *config_warning.lock().unwrap() = Some(warning);
+            None
+        }
+    }
+    .unwrap_or_default();
+
+    if let Some(profile) = &cli.profile
+        && let Err(e) = config.set_profile_override(profile)
+    {
+        println!("{}", e);
+        return ExitCode::FAILURE;
+    }
 
     // Create shared state
+    let muscle_mappings = Arc::new(Mutex::new(Vec::new()));
     let contact_states = Arc::new(Mutex::new(HashMap::new()));
-    let needs_connect = Arc::new(Mutex::new(true));
     let toggle_states = Arc::new(Mutex::new(HashMap::<String, bool>::new()));
-    let ip_address = Arc::new(Mutex::new(None::<String>));
+    let grip_states = Arc::new(Mutex::new(HashMap::<String, bool>::new()));
+    let connection = Arc::new(Mutex::new(Connection {
+        ip_override: cli.ip.clone(),
+        ..Connection::default()
+    }));
+    let active_avatar = Arc::new(Mutex::new(None::<String>));
+    let osc_address = SocketAddr::new(
+        cli.osc_ip.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        cli.osc_port,
+    );
+
+    // Load muscle mappings and IP address from the active profile
+    apply_profile(config.active_profile(), &muscle_mappings, &connection);
+    let config = Arc::new(Mutex::new(config));
 
     // Initialize all supported parameters
     {
@@ -42,30 +98,153 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
-    // Load IP address from config if available
-    if let Some(config) = load_config() {
-        if let Some(ip) = config.ip_address {
-            if !ip.is_empty() {
-                let mut ip_lock = ip_address.lock().unwrap();
-                *ip_lock = Some(ip);
-            }
-        }
+    // Reload the config when it is edited outside the app
+    if let Err(e) = start_config_watcher(
+        config.clone(),
+        muscle_mappings.clone(),
+        connection.clone(),
+        config_warning.clone(),
+    ) {
+        println!("Error watching config file, changes need a restart: {}", e);
     }
 
     // Start the OWO thread
     start_owo_thread(
         contact_states.clone(),
-        needs_connect.clone(),
+        connection.clone(),
         muscle_mappings.clone(),
         toggle_states.clone(),
-        ip_address.clone(),
+        grip_states.clone(),
+        config.clone(),
+        osc_address,
     );
 
     // Setup OSC listener
-    let _vrcchat_osc = setup_osc_listener(contact_states.clone(), toggle_states.clone())
-        .await
-        .unwrap();
+    let _vrcchat_osc = setup_osc_listener(
+        contact_states.clone(),
+        toggle_states.clone(),
+        grip_states.clone(),
+        config.clone(),
+        muscle_mappings.clone(),
+        connection.clone(),
+        active_avatar.clone(),
+        cli.osc_ip,
+        osc_address,
+    )
+    .await
+    .unwrap();
+
+    #[cfg(feature = "gui")]
+    let result = if cli.headless {
+        run_headless(config.clone(), connection.clone(), active_avatar.clone()).await
+    } else {
+        // Start the UI
+        setup_ui(
+            config.clone(),
+            muscle_mappings.clone(),
+            connection.clone(),
+            active_avatar.clone(),
+            config_warning.clone(),
+        )
+    };
+    #[cfg(not(feature = "gui"))]
+    let result = run_headless(config.clone(), connection.clone(), active_avatar.clone()).await;
+
+    // Write changes that are still waiting for the save delay
+    flush_saves();
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            println!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    // Start the UI
-    setup_ui(muscle_mappings.clone(), needs_connect.clone(), ip_address.clone())
+fn print_config() -> ExitCode {
+    match read_config() {
+        Ok(config) => {
+            let config = config.unwrap_or_default();
+            match serde_json::to_string_pretty(&config) {
+                Ok(json) => {
+                    println!("{}", json);
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    println!("Error printing config: {}", e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Err(e) => {
+            println!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn validate_config() -> ExitCode {
+    let config_path = get_config_path();
+    match read_config() {
+        Ok(Some(_)) => {
+            println!("{} is valid", config_path.display());
+            ExitCode::SUCCESS
+        }
+        Ok(None) => {
+            println!(
+                "{} does not exist, default settings will be used",
+                config_path.display()
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("{} is invalid: {}", config_path.display(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn export_profile_file(profile: Option<&str>, path: &Path) -> ExitCode {
+    let config = match read_config() {
+        Ok(config) => config.unwrap_or_default(),
+        Err(e) => {
+            println!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(profile) = config.profile(profile.unwrap_or(&config.active_profile)) else {
+        println!("Unknown profile '{}'", profile.unwrap_or_default());
+        return ExitCode::FAILURE;
+    };
+
+    match export_profile(profile, path) {
+        Ok(()) => {
+            println!("Exported profile '{}' to {}", profile.name, path.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("Error exporting profile: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// A running app picks up the new profile through the config watcher
+fn import_profile_file(path: &Path) -> ExitCode {
+    let result = load_config().and_then(|config| {
+        let mut config = config.unwrap_or_default();
+        let name = config.add_profile(import_profile(path)?)?;
+        save_config(&config)?;
+        Ok(name)
+    });
+    match result {
+        Ok(name) => {
+            println!("Imported profile '{}' from {}", name, path.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("Error importing profile: {}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -1,91 +1,1203 @@
-use owo_skin::muscles::{Muscle, MuscleWithIntensity};
+use crate::curve::ResponseCurves;
+use crate::envelope::{EnvelopeState, Envelopes};
+use crate::gesture::ContactEvent;
+use crate::smoothing::{FilterState, Smoothing};
+use owo_skin::muscles::{Muscle, MuscleWithIntensity, ALL};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
 
+pub const MAX_INTENSITY: u8 = 100;
+pub const MAX_WEIGHT: f32 = 2.0;
+pub const MAX_SCALE: f32 = 2.0;
+// Depths older than this are not used to estimate the velocity
+pub const DEPTH_HISTORY: Duration = Duration::from_millis(150);
+// Depth per second from which a contact counts as an impact, and the one that gets the full
+// intensity, reaching full depth in 0.1 s
+pub const DEFAULT_MIN_DEPTH_VELOCITY: f32 = 1.5;
+pub const DEFAULT_MAX_DEPTH_VELOCITY: f32 = 10.0;
+// Fastest velocity contact of the prefab with the default generator settings
+pub const DEFAULT_MAX_VELOCITY: f32 = 5.0;
+// Muscles further than this from a contact point are not driven by it
+pub const DEFAULT_POINT_RADIUS: f32 = 0.6;
+
+// Intensities of parameters added by the user
+pub const DEFAULT_INTENSITY_TOUCH: u8 = 20;
+pub const DEFAULT_INTENSITY_IMPACT: u8 = 60;
+pub const DEFAULT_INTENSITY_STAB: u8 = 100;
+pub const DEFAULT_INTENSITY_PROJECTILE: u8 = 80;
+pub const DEFAULT_INTENSITY_GRAB: u8 = 40;
+
+// Applied to every interaction type of a parameter, after its curve and weights
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct IntensityLimits {
+    // Inputs up to this fraction of the full depth or speed are ignored
+    #[serde(default)]
+    pub deadzone: f32,
+    // Lowest intensity once the input leaves the deadzone, the vest is hard to feel below it.
+    // Envelopes and adaptation still fade below it.
+    #[serde(default)]
+    pub min_intensity: u8,
+    #[serde(default = "max_intensity")]
+    pub max_intensity: u8,
+}
+
+// Lowers the intensity of a touch that stays still for a long time, like skin getting used to it
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Adaptation {
+    pub enabled: bool,
+    // Fraction of the intensity left once fully adapted
+    pub floor: f32,
+    // Time constant of fading toward the floor
+    pub fade_ms: u32,
+    // Time constant of recovering once the contact ends
+    pub recover_ms: u32,
+    // Depth changes up to this count as the same touch
+    pub tolerance: f32,
+}
+
+// Multipliers for the whole vest, on top of the intensities of each parameter
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IntensityScales {
+    pub master: f32,
+    pub touch: f32,
+    pub impact: f32,
+    pub stab: f32,
+    pub projectile: f32,
+    pub grab: f32,
+}
+
+// Position on the body map. x goes from the right (-1) to the left (1) side, y from the waist (0)
+// to the shoulders (1) and z from the back (-0.5) to the front (0.5).
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BodyPosition {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+// A virtual contact between muscles, it drives the muscles around it by distance
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ContactPoint {
+    #[serde(flatten)]
+    pub position: BodyPosition,
+    #[serde(default = "point_radius")]
+    pub radius: f32,
+}
+
+// Named points for contacts that have no muscle of their own. The sides are between the arm and
+// the front and back muscles, so they reach further.
+pub const BODY_POINTS: [(&str, ContactPoint); 8] = [
+    (
+        "Sternum",
+        body_point_at(0.0, 0.75, 0.5, DEFAULT_POINT_RADIUS),
+    ),
+    ("Navel", body_point_at(0.0, 0.25, 0.5, DEFAULT_POINT_RADIUS)),
+    (
+        "Spine",
+        body_point_at(0.0, 0.75, -0.5, DEFAULT_POINT_RADIUS),
+    ),
+    (
+        "Lower_Back",
+        body_point_at(0.0, 0.25, -0.5, DEFAULT_POINT_RADIUS),
+    ),
+    ("Side_R", body_point_at(-0.75, 0.45, 0.0, 0.8)),
+    ("Side_L", body_point_at(0.75, 0.45, 0.0, 0.8)),
+    (
+        "Shoulder_R",
+        body_point_at(-0.8, 0.95, 0.0, DEFAULT_POINT_RADIUS),
+    ),
+    (
+        "Shoulder_L",
+        body_point_at(0.8, 0.95, 0.0, DEFAULT_POINT_RADIUS),
+    ),
+];
+
+// A contact parameter drives each of its muscles with its intensity scaled by the link's weight
+#[derive(Clone, Copy)]
+pub struct MuscleLink {
+    pub muscle: Muscle,
+    pub weight: f32,
+}
+
+#[derive(Clone)]
+pub struct ParameterMapping {
+    pub name: String,
+    pub intensity_touch: u8,
+    pub intensity_impact: u8,
+    pub intensity_stab: u8,
+    pub intensity_projectile: u8,
+    pub intensity_grab: u8,
+    pub curves: ResponseCurves,
+    pub limits: IntensityLimits,
+    pub adaptation: Adaptation,
+    pub smoothing: Smoothing,
+    pub links: Vec<MuscleLink>,
+}
+
+// All contact parameters of a prefab and the muscles they drive. A parameter can drive several
+// muscles and a muscle can be driven by several parameters.
+#[derive(Clone, Default)]
+pub struct MuscleMapping {
+    pub parameters: Vec<ParameterMapping>,
+}
+
+// Where the velocity of impacts comes from
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VelocitySource {
+    // The velocity/<N> bool contacts of the prefab
+    #[default]
+    Contacts,
+    // How fast the depth of the contact grows, for avatars without velocity contacts
+    Depth,
+    Both,
+}
+
+// Maps the velocity/<N> contacts of the prefab to the impact intensity. Contacts at the minimum
+// or slower are ignored and the maximum gets the full intensity.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VelocityRange {
+    pub min: f32,
+    pub max: f32,
+    // Limits faster contacts to the full intensity
+    pub clamp: bool,
+    // Uses the fastest velocity contact found on the avatar as the maximum
+    pub auto_detect: bool,
+}
+
+// Maps the estimated depth per second of a contact to the impact intensity. Contacts moving in
+// slower than the minimum are not impacts and the maximum gets the full intensity.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DepthVelocityRange {
+    pub min: f32,
+    pub max: f32,
+}
+
+// How contacts of different interaction types on the vest at the same time are played
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MixPolicy {
+    // Only the highest interaction type on the whole vest is played
+    WinnerTakesAll,
+    // Each muscle plays its strongest interaction
+    #[default]
+    PerMuscleMax,
+    // Each muscle plays all of its interactions at once
+    Blend,
+}
+
+// The mapping of one prefab on the avatar, its parameters are named <prefix>/<parameter>/...
+#[derive(Clone)]
+pub struct InstanceMappings {
+    pub prefix: String,
+    pub mapping: MuscleMapping,
+    pub velocity_source: VelocitySource,
+    pub velocity_range: VelocityRange,
+    pub depth_velocity_range: DepthVelocityRange,
+}
+
+// Ordered by precedence, higher types win when several land on the same muscle
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
 pub enum InteractionType {
     Touch,
+    Grab,
     Impact,
     Stab,
+    Projectile,
 }
 
+pub const INTERACTION_TYPES: [InteractionType; 5] = [
+    InteractionType::Touch,
+    InteractionType::Grab,
+    InteractionType::Impact,
+    InteractionType::Stab,
+    InteractionType::Projectile,
+];
+
 #[derive(Clone)]
 pub struct MuscleState {
     pub interaction_type: InteractionType,
     pub depth: f32,
+    // Fraction of the velocity range, 1 for the fastest impact
     pub velocity: f32,
+    // Recent depths with the time they were received, oldest first
+    pub depth_history: VecDeque<(Instant, f32)>,
+    // Set while the estimated velocity is above the minimum, so one approach is one impact
+    pub approaching: bool,
+    // The interaction that is playing, it can outlast the contact
+    pub envelope: Option<EnvelopeState>,
+    // Fraction of the touch intensity that is felt, lowered by adaptation
+    pub sensitivity: f32,
+    // Depth the touch is adapting to
+    pub adapted_depth: f32,
+    pub adapted_at: Option<Instant>,
+    pub depth_filter: FilterState,
+    // A hand or grab contact is on, touches are played as grabs
+    pub grabbing: bool,
+    // Contacts the gesture recogniser has not seen end yet
+    pub contacts: Vec<ContactEvent>,
 }
 
-impl MuscleState {
-    pub fn default() -> Self {
+impl Default for MuscleState {
+    fn default() -> Self {
         Self {
             interaction_type: InteractionType::Touch,
             depth: 0.0,
             velocity: 0.0,
+            depth_history: VecDeque::new(),
+            approaching: false,
+            envelope: None,
+            sensitivity: 1.0,
+            adapted_depth: 0.0,
+            adapted_at: None,
+            depth_filter: FilterState::default(),
+            grabbing: false,
+            contacts: Vec::new(),
+        }
+    }
+}
+
+impl MuscleState {
+    // Called once per tick. Impacts, stabs and projectiles received since the last tick start their
+    // envelope and the state goes back to touch for the next one. Touches and grabs follow the depth
+    // of the contact, touches are grabs while grabbing or while the grip is on.
+    pub fn update_envelope(&mut self, envelopes: &Envelopes, grip: bool, now: Instant) {
+        if self.interaction_type != InteractionType::Touch {
+            let mut envelope =
+                EnvelopeState::new(self.interaction_type, self.depth, self.velocity, now);
+            envelope.released = Some(now);
+            self.envelope = Some(envelope);
+            self.interaction_type = InteractionType::Touch;
+            self.velocity = 0.0;
+            return;
+        }
+
+        if let Some(envelope) = &self.envelope
+            && envelope.level(envelopes, now).is_none()
+        {
+            self.envelope = None;
+        }
+
+        let touching = self.depth > 0.0;
+        let sustained = if self.grabbing || grip {
+            InteractionType::Grab
+        } else {
+            InteractionType::Touch
+        };
+        match &mut self.envelope {
+            // Touches and grabs wait for the impact, stab or projectile to finish
+            Some(envelope) if !envelope.interaction_type.is_sustained() => {}
+            Some(envelope)
+                if touching
+                    && envelope.released.is_none()
+                    && envelope.interaction_type == sustained =>
+            {
+                envelope.depth = self.depth;
+            }
+            Some(envelope) if !touching => {
+                envelope.released.get_or_insert(now);
+            }
+            // A touch that turns into a grab starts over with the envelope of the grab
+            _ if touching => {
+                self.envelope = Some(EnvelopeState::new(sustained, self.depth, 0.0, now));
+            }
+            _ => {}
+        }
+    }
+
+    // Called once per tick, fades the sensitivity while the depth stays within the tolerance of
+    // where the touch settled
+    pub fn update_adaptation(&mut self, adaptation: &Adaptation, now: Instant) {
+        if !adaptation.enabled {
+            self.sensitivity = 1.0;
+            self.adapted_at = None;
+            return;
+        }
+        let elapsed_ms = self
+            .adapted_at
+            .map_or(0.0, |time| now.duration_since(time).as_secs_f32() * 1000.0);
+        self.adapted_at = Some(now);
+
+        if self.depth <= 0.0 {
+            self.adapted_depth = 0.0;
+            let remaining = decay(elapsed_ms, adaptation.recover_ms);
+            self.sensitivity = 1.0 - (1.0 - self.sensitivity) * remaining;
+        } else if self.adapted_depth > 0.0
+            && (self.depth - self.adapted_depth).abs() > adaptation.tolerance
+        {
+            // A moving touch is felt again in full
+            self.adapted_depth = self.depth;
+            self.sensitivity = 1.0;
+        } else {
+            if self.adapted_depth <= 0.0 {
+                self.adapted_depth = self.depth;
+            }
+            let remaining = decay(elapsed_ms, adaptation.fade_ms);
+            self.sensitivity = adaptation.floor + (self.sensitivity - adaptation.floor) * remaining;
+        }
+    }
+
+    // The interaction to play and the level of its envelope, None when nothing is playing
+    pub fn playing(&self, envelopes: &Envelopes, now: Instant) -> Option<(MuscleState, f32)> {
+        let envelope = self.envelope.as_ref()?;
+        let level = envelope.level(envelopes, now)?;
+        let state = MuscleState {
+            interaction_type: envelope.interaction_type,
+            depth: envelope.depth,
+            velocity: envelope.velocity,
+            ..MuscleState::default()
+        };
+        Some((state, level))
+    }
+
+    pub fn record_depth(&mut self, depth: f32, smoothing: &Smoothing, time: Instant) {
+        self.depth = self.depth_filter.filter(smoothing, depth, time);
+        ContactEvent::record(&mut self.contacts, self.depth, time);
+        // Impacts are estimated from the raw depths, smoothing would slow them down
+        self.depth_history.push_back((time, depth));
+        while let Some((oldest, _)) = self.depth_history.front()
+            && time.duration_since(*oldest) > DEPTH_HISTORY
+        {
+            self.depth_history.pop_front();
+        }
+    }
+
+    // Approach speed in depth per second, a least squares fit so a single jittery depth does not
+    // count as an impact. 0 while the contact stays or moves out.
+    pub fn depth_velocity(&self) -> f32 {
+        let Some((start, _)) = self.depth_history.front() else {
+            return 0.0;
+        };
+        let samples: Vec<(f32, f32)> = self
+            .depth_history
+            .iter()
+            .map(|(time, depth)| (time.duration_since(*start).as_secs_f32(), *depth))
+            .collect();
+        let count = samples.len() as f32;
+        let mean_time = samples.iter().map(|(time, _)| time).sum::<f32>() / count;
+        let mean_depth = samples.iter().map(|(_, depth)| depth).sum::<f32>() / count;
+        let (covariance, variance) =
+            samples
+                .iter()
+                .fold((0.0, 0.0), |(cov, var), (time, depth)| {
+                    let dt = time - mean_time;
+                    (cov + dt * (depth - mean_depth), var + dt * dt)
+                });
+        if variance <= 0.0 {
+            return 0.0;
+        }
+        (covariance / variance).max(0.0)
+    }
+
+    // Starts an impact when the contact moves in fast enough, its velocity is the fastest estimate
+    // until the contact slows down
+    pub fn estimate_impact(&mut self, range: &DepthVelocityRange) {
+        let velocity = self.depth_velocity();
+        let approaching = velocity >= range.min;
+        if approaching && !self.approaching && self.interaction_type < InteractionType::Impact {
+            self.interaction_type = InteractionType::Impact;
+        }
+        if approaching && self.interaction_type == InteractionType::Impact {
+            self.velocity = self.velocity.max(range.normalize(velocity));
+        }
+        self.approaching = approaching;
+    }
+}
+
+impl Default for VelocityRange {
+    fn default() -> Self {
+        Self {
+            min: 0.0,
+            max: DEFAULT_MAX_VELOCITY,
+            clamp: false,
+            auto_detect: true,
+        }
+    }
+}
+
+impl VelocityRange {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.min.is_finite() || self.min < 0.0 {
+            return Err(format!("minimum must be at least 0, found {}", self.min));
+        }
+        if !self.max.is_finite() || self.max <= self.min {
+            return Err(format!(
+                "maximum {} must be above the minimum {}",
+                self.max, self.min
+            ));
+        }
+        Ok(())
+    }
+
+    // The detected maximum is used instead of the configured one when detection is enabled
+    pub fn normalize(&self, velocity: f32, detected_max: Option<f32>) -> f32 {
+        let max = detected_max
+            .filter(|_| self.auto_detect)
+            .unwrap_or(self.max);
+        if max <= self.min {
+            return if velocity > self.min { 1.0 } else { 0.0 };
+        }
+        let fraction = ((velocity - self.min) / (max - self.min)).max(0.0);
+        if self.clamp {
+            fraction.min(1.0)
+        } else {
+            fraction
+        }
+    }
+}
+
+impl Default for DepthVelocityRange {
+    fn default() -> Self {
+        Self {
+            min: DEFAULT_MIN_DEPTH_VELOCITY,
+            max: DEFAULT_MAX_DEPTH_VELOCITY,
+        }
+    }
+}
+
+impl DepthVelocityRange {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.min.is_finite() || self.min <= 0.0 {
+            return Err(format!("minimum must be above 0, found {}", self.min));
+        }
+        if !self.max.is_finite() || self.max < self.min {
+            return Err(format!(
+                "maximum {} must be at least the minimum {}",
+                self.max, self.min
+            ));
         }
+        Ok(())
+    }
+
+    pub fn normalize(&self, velocity: f32) -> f32 {
+        velocity / self.max
+    }
+}
+
+impl VelocitySource {
+    pub fn uses_contacts(&self) -> bool {
+        matches!(self, VelocitySource::Contacts | VelocitySource::Both)
+    }
+
+    pub fn uses_depth(&self) -> bool {
+        matches!(self, VelocitySource::Depth | VelocitySource::Both)
     }
+}
 
-    pub fn should_send_sensation(&self) -> bool {
-        self.depth > 0.0 || self.velocity > 0.0 && self.interaction_type == InteractionType::Impact
+impl InteractionType {
+    // Lasts while the contact does, the others are single hits
+    pub fn is_sustained(&self) -> bool {
+        matches!(self, InteractionType::Touch | InteractionType::Grab)
     }
 }
 
-pub fn default_muscle_mappings() -> [(&'static str, Muscle, u8, u8, u8); 10] {
-    [
-        ("Pectoral_R", Muscle::PectoralR, 20, 60, 100),
-        ("Pectoral_L", Muscle::PectoralL, 20, 60, 100),
-        ("Abdominal_R", Muscle::AbdominalR, 15, 50, 100),
-        ("Abdominal_L", Muscle::AbdominalL, 15, 50, 100),
-        ("Arm_R", Muscle::ArmR, 15, 30, 80),
-        ("Arm_L", Muscle::ArmL, 15, 30, 80),
-        ("Dorsal_R", Muscle::DorsalR, 15, 50, 100),
-        ("Dorsal_L", Muscle::DorsalL, 15, 50, 100),
-        ("Lumbar_R", Muscle::LumbarR, 20, 60, 100),
-        ("Lumbar_L", Muscle::LumbarL, 20, 60, 100),
-    ]
+impl Default for IntensityLimits {
+    fn default() -> Self {
+        Self {
+            deadzone: 0.0,
+            min_intensity: 0,
+            max_intensity: MAX_INTENSITY,
+        }
+    }
 }
 
-pub fn get_muscle_for_parameter(
+impl Default for Adaptation {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            floor: 0.3,
+            fade_ms: 30_000,
+            recover_ms: 500,
+            tolerance: 0.05,
+        }
+    }
+}
+
+impl Adaptation {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.floor) {
+            return Err(format!(
+                "floor must be between 0 and 1, found {}",
+                self.floor
+            ));
+        }
+        if self.fade_ms == 0 {
+            return Err("fade time must be above 0".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.tolerance) {
+            return Err(format!(
+                "tolerance must be between 0 and 1, found {}",
+                self.tolerance
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for IntensityScales {
+    fn default() -> Self {
+        Self {
+            master: 1.0,
+            touch: 1.0,
+            impact: 1.0,
+            stab: 1.0,
+            projectile: 1.0,
+            grab: 1.0,
+        }
+    }
+}
+
+impl BodyPosition {
+    pub const fn new(x: f32, y: f32, z: f32) -> Self {
+        Self { x, y, z }
+    }
+
+    pub fn distance(&self, other: &BodyPosition) -> f32 {
+        ((self.x - other.x).powi(2) + (self.y - other.y).powi(2) + (self.z - other.z).powi(2))
+            .sqrt()
+    }
+}
+
+impl ContactPoint {
+    // The nearest muscle gets the full intensity, the others fall off linearly up to the radius
+    pub fn links(&self) -> Vec<MuscleLink> {
+        let falloffs: Vec<(Muscle, f32)> = ALL
+            .into_iter()
+            .map(|muscle| {
+                let distance = muscle_position(muscle).distance(&self.position);
+                (muscle, 1.0 - distance / self.radius)
+            })
+            .filter(|(_, falloff)| *falloff > 0.0)
+            .collect();
+        let strongest = falloffs
+            .iter()
+            .map(|(_, falloff)| *falloff)
+            .fold(0.0, f32::max);
+        falloffs
+            .into_iter()
+            .map(|(muscle, falloff)| MuscleLink {
+                muscle,
+                weight: falloff / strongest,
+            })
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.radius.is_finite() || self.radius <= 0.0 {
+            return Err(format!("radius must be above 0, found {}", self.radius));
+        }
+        if self.links().is_empty() {
+            return Err(format!("no muscle is within {} of the point", self.radius));
+        }
+        Ok(())
+    }
+}
+
+impl IntensityScales {
+    pub fn factor(&self, interaction_type: InteractionType) -> f32 {
+        let scale = match interaction_type {
+            InteractionType::Touch => self.touch,
+            InteractionType::Impact => self.impact,
+            InteractionType::Stab => self.stab,
+            InteractionType::Projectile => self.projectile,
+            InteractionType::Grab => self.grab,
+        };
+        self.master * scale
+    }
+
+    // Name and value of each scale, as used in the config and in OSC parameters
+    pub fn values(&self) -> [(&'static str, f32); 6] {
+        [
+            ("master", self.master),
+            ("touch", self.touch),
+            ("impact", self.impact),
+            ("stab", self.stab),
+            ("projectile", self.projectile),
+            ("grab", self.grab),
+        ]
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut f32> {
+        match name {
+            "master" => Some(&mut self.master),
+            "touch" => Some(&mut self.touch),
+            "impact" => Some(&mut self.impact),
+            "stab" => Some(&mut self.stab),
+            "projectile" => Some(&mut self.projectile),
+            "grab" => Some(&mut self.grab),
+            _ => None,
+        }
+    }
+}
+
+impl IntensityLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..1.0).contains(&self.deadzone) {
+            return Err(format!(
+                "deadzone must be at least 0 and below 1, found {}",
+                self.deadzone
+            ));
+        }
+        if self.max_intensity > MAX_INTENSITY {
+            return Err(format!(
+                "maximum intensity is {}, highest allowed is {}",
+                self.max_intensity, MAX_INTENSITY
+            ));
+        }
+        if self.min_intensity > self.max_intensity {
+            return Err(format!(
+                "minimum intensity {} is above the maximum {}",
+                self.min_intensity, self.max_intensity
+            ));
+        }
+        Ok(())
+    }
+
+    // Removes the deadzone and stretches the rest of the input back to the full range
+    pub fn input(&self, input: f32) -> Option<f32> {
+        (input > self.deadzone).then(|| (input - self.deadzone) / (1.0 - self.deadzone))
+    }
+
+    // Limits the intensity of a contact, then applies the fade of its envelope and adaptation, so
+    // a contact fading out still goes below the minimum. Rounds instead of truncating, so small
+    // intensities are not lost.
+    pub fn output(&self, intensity: f32, fade: f32) -> u8 {
+        if intensity <= 0.0 {
+            return 0;
+        }
+        let intensity = intensity.clamp(self.min_intensity as f32, self.max_intensity as f32);
+        (intensity * fade).round() as u8
+    }
+}
+
+impl ParameterMapping {
+    pub fn new(name: &str, intensity_touch: u8, intensity_impact: u8, intensity_stab: u8) -> Self {
+        Self {
+            name: name.to_string(),
+            intensity_touch,
+            intensity_impact,
+            intensity_stab,
+            intensity_projectile: DEFAULT_INTENSITY_PROJECTILE,
+            intensity_grab: DEFAULT_INTENSITY_GRAB,
+            curves: ResponseCurves::default(),
+            limits: IntensityLimits::default(),
+            adaptation: Adaptation::default(),
+            smoothing: Smoothing::default(),
+            links: Vec::new(),
+        }
+    }
+
+    pub fn with_link(mut self, muscle: Muscle, weight: f32) -> Self {
+        self.links.push(MuscleLink { muscle, weight });
+        self
+    }
+
+    // Intensity before weights and limits, 0 inside the deadzone
+    pub fn intensity(&self, state: &MuscleState) -> f32 {
+        let (intensity, input) = match state.interaction_type {
+            InteractionType::Touch => (self.intensity_touch, state.depth),
+            InteractionType::Impact => (self.intensity_impact, state.velocity),
+            InteractionType::Stab => (self.intensity_stab, 1.0),
+            InteractionType::Projectile => (self.intensity_projectile, 1.0),
+            InteractionType::Grab => (self.intensity_grab, state.depth),
+        };
+        let Some(input) = self.limits.input(input) else {
+            return 0.0;
+        };
+        intensity as f32 * self.curves.get(state.interaction_type).evaluate(input)
+    }
+}
+
+impl MuscleMapping {
+    pub fn parameter(&self, name: &str) -> Option<&ParameterMapping> {
+        self.parameters
+            .iter()
+            .find(|parameter| parameter.name == name)
+    }
+}
+
+pub fn default_muscle_mapping() -> MuscleMapping {
+    MuscleMapping {
+        parameters: vec![
+            ParameterMapping::new("Pectoral_R", 20, 60, 100).with_link(Muscle::PectoralR, 1.0),
+            ParameterMapping::new("Pectoral_L", 20, 60, 100).with_link(Muscle::PectoralL, 1.0),
+            ParameterMapping::new("Abdominal_R", 15, 50, 100).with_link(Muscle::AbdominalR, 1.0),
+            ParameterMapping::new("Abdominal_L", 15, 50, 100).with_link(Muscle::AbdominalL, 1.0),
+            ParameterMapping::new("Arm_R", 15, 30, 80).with_link(Muscle::ArmR, 1.0),
+            ParameterMapping::new("Arm_L", 15, 30, 80).with_link(Muscle::ArmL, 1.0),
+            ParameterMapping::new("Dorsal_R", 15, 50, 100).with_link(Muscle::DorsalR, 1.0),
+            ParameterMapping::new("Dorsal_L", 15, 50, 100).with_link(Muscle::DorsalL, 1.0),
+            ParameterMapping::new("Lumbar_R", 20, 60, 100).with_link(Muscle::LumbarR, 1.0),
+            ParameterMapping::new("Lumbar_L", 20, 60, 100).with_link(Muscle::LumbarL, 1.0),
+        ],
+    }
+}
+
+// Splits a parameter into the instance it belongs to and the rest of its name. Prefixes may
+// contain slashes, so the longest matching prefix wins.
+pub fn split_parameter<'a, 'b>(
+    instances: &'a [InstanceMappings],
+    parameter: &'b str,
+) -> Option<(&'a InstanceMappings, &'b str)> {
+    instances
+        .iter()
+        .filter_map(|instance| {
+            parameter
+                .strip_prefix(instance.prefix.as_str())
+                .and_then(|rest| rest.strip_prefix('/'))
+                .map(|rest| (instance, rest))
+        })
+        .max_by_key(|(instance, _)| instance.prefix.len())
+}
+
+pub fn find_parameter<'a>(
+    parameter: &str,
+    instances: &'a [InstanceMappings],
+) -> Option<&'a ParameterMapping> {
+    let (instance, parameter) = split_parameter(instances, parameter)?;
+    instance.mapping.parameter(parameter)
+}
+
+// The muscles driven by a contact and the intensity for each of them. The scale multiplies the
+// intensity before the limits, the fade of the envelope level and adaptation after them.
+pub fn get_muscles_for_parameter(
     parameter: &str,
     state: &MuscleState,
-    mappings: &[(&str, Muscle, u8, u8, u8); 10],
-) -> Option<MuscleWithIntensity> {
-    mappings
+    instances: &[InstanceMappings],
+    scale: f32,
+    fade: f32,
+) -> Vec<(Muscle, u8)> {
+    let Some(parameter) = find_parameter(parameter, instances) else {
+        return Vec::new();
+    };
+    let intensity = parameter.intensity(state) * scale;
+    parameter
+        .links
         .iter()
-        .find(|(param, _, _, _, _)| *param == parameter)
-        .map(
-            |(_, muscle, intensity_touch, intensity_impact, intensity_stab)| {
-                let intensity = match state.interaction_type {
-                    InteractionType::Touch => *intensity_touch as f32 * state.depth,
-                    InteractionType::Impact => *intensity_impact as f32 * state.velocity / 5.0,
-                    InteractionType::Stab => *intensity_stab as f32,
-                };
-                MuscleWithIntensity::new(*muscle, intensity as u8)
-            },
-        )
+        .map(|link| {
+            (
+                link.muscle,
+                parameter.limits.output(intensity * link.weight, fade),
+            )
+        })
+        .collect()
 }
 
 pub fn get_intensity(
     parameter: &str,
     state: &MuscleState,
-    mappings: &[(&str, Muscle, u8, u8, u8); 10],
+    instances: &[InstanceMappings],
+    scale: f32,
+    fade: f32,
 ) -> Option<u8> {
-    mappings
-        .iter()
-        .find(|(param, _, _, _, _)| *param == parameter)
-        .map(
-            |(_, _, intensity_touch, intensity_impact, intensity_stab)| {
-                let intensity = match state.interaction_type {
-                    InteractionType::Touch => *intensity_touch as f32 * state.depth,
-                    InteractionType::Impact => *intensity_impact as f32 * state.velocity / 5.0,
-                    InteractionType::Stab => *intensity_stab as f32,
-                };
-                intensity as u8
-            },
-        )
-}
-
-pub fn get_supported_parameters(mappings: &[(&str, Muscle, u8, u8, u8); 10]) -> Vec<String> {
-    mappings
+    find_parameter(parameter, instances).map(|parameter| {
+        let intensity = parameter.intensity(state) * scale;
+        parameter.limits.output(intensity, fade)
+    })
+}
+
+// Several contacts can drive the same muscle, the strongest one wins
+pub fn merge_muscle_intensities(
+    intensities: impl IntoIterator<Item = (Muscle, u8)>,
+) -> Vec<MuscleWithIntensity> {
+    let mut merged: BTreeMap<u8, (Muscle, u8)> = BTreeMap::new();
+    for (muscle, intensity) in intensities {
+        let entry = merged.entry(muscle as u8).or_insert((muscle, intensity));
+        entry.1 = entry.1.max(intensity);
+    }
+    merged
+        .into_values()
+        .map(|(muscle, intensity)| MuscleWithIntensity::new(muscle, intensity))
+        .collect()
+}
+
+// The interaction types playing on the vest, lowest first, and the intensity of each muscle. The
+// app plays one sensation at a time, so all interactions are sent together. Several contacts of
+// one type on a muscle play the strongest of them.
+pub fn mix_interactions(
+    intensities: impl IntoIterator<Item = (InteractionType, Muscle, u8)>,
+    policy: MixPolicy,
+) -> (Vec<InteractionType>, Vec<(Muscle, u8)>) {
+    let mut intensities: Vec<_> = intensities
+        .into_iter()
+        .filter(|(_, _, intensity)| *intensity > 0)
+        .collect();
+    match policy {
+        MixPolicy::WinnerTakesAll => {
+            let winner = intensities
+                .iter()
+                .map(|(interaction_type, _, _)| *interaction_type)
+                .max();
+            intensities.retain(|(interaction_type, _, _)| Some(*interaction_type) == winner);
+        }
+        MixPolicy::PerMuscleMax => {
+            // Ties go to the higher interaction type
+            let mut strongest: BTreeMap<u8, (InteractionType, Muscle, u8)> = BTreeMap::new();
+            for (interaction_type, muscle, intensity) in intensities {
+                let entry =
+                    strongest
+                        .entry(muscle as u8)
+                        .or_insert((interaction_type, muscle, intensity));
+                if (intensity, interaction_type) > (entry.2, entry.0) {
+                    *entry = (interaction_type, muscle, intensity);
+                }
+            }
+            intensities = strongest.into_values().collect();
+        }
+        MixPolicy::Blend => {}
+    }
+
+    let mut strongest: BTreeMap<(u8, InteractionType), (Muscle, u8)> = BTreeMap::new();
+    for (interaction_type, muscle, intensity) in intensities {
+        let entry = strongest
+            .entry((muscle as u8, interaction_type))
+            .or_insert((muscle, intensity));
+        entry.1 = entry.1.max(intensity);
+    }
+    let mut interaction_types: Vec<_> = strongest
+        .keys()
+        .map(|(_, interaction_type)| *interaction_type)
+        .collect();
+    interaction_types.sort();
+    interaction_types.dedup();
+    // Only blending leaves several types on a muscle, they add up
+    let mut muscles: BTreeMap<u8, (Muscle, u8)> = BTreeMap::new();
+    for (muscle, intensity) in strongest.into_values() {
+        let entry = muscles.entry(muscle as u8).or_insert((muscle, 0));
+        entry.1 = entry.1.saturating_add(intensity).min(MAX_INTENSITY);
+    }
+    (interaction_types, muscles.into_values().collect())
+}
+
+pub fn get_supported_parameters(instances: &[InstanceMappings]) -> Vec<String> {
+    instances
         .iter()
-        .map(|(param, _, _, _, _)| param.to_string())
+        .flat_map(|instance| {
+            instance
+                .mapping
+                .parameters
+                .iter()
+                .map(|parameter| format!("{}/{}", instance.prefix, parameter.name))
+        })
         .collect()
-}
\ No newline at end of file
+}
+
+// Where each muscle of the vest sits on the body map
+pub fn muscle_position(muscle: Muscle) -> BodyPosition {
+    let (x, y, z) = match muscle {
+        Muscle::PectoralR => (-0.45, 0.75, 0.5),
+        Muscle::PectoralL => (0.45, 0.75, 0.5),
+        Muscle::AbdominalR => (-0.35, 0.3, 0.5),
+        Muscle::AbdominalL => (0.35, 0.3, 0.5),
+        Muscle::ArmR => (-1.1, 0.85, 0.0),
+        Muscle::ArmL => (1.1, 0.85, 0.0),
+        Muscle::DorsalR => (-0.45, 0.75, -0.5),
+        Muscle::DorsalL => (0.45, 0.75, -0.5),
+        Muscle::LumbarR => (-0.35, 0.3, -0.5),
+        Muscle::LumbarL => (0.35, 0.3, -0.5),
+    };
+    BodyPosition::new(x, y, z)
+}
+
+pub fn body_point(name: &str) -> Option<ContactPoint> {
+    BODY_POINTS
+        .iter()
+        .find(|(point, _)| *point == name)
+        .map(|(_, point)| *point)
+}
+
+const fn body_point_at(x: f32, y: f32, z: f32, radius: f32) -> ContactPoint {
+    ContactPoint {
+        position: BodyPosition::new(x, y, z),
+        radius,
+    }
+}
+
+pub fn interaction_name(interaction_type: InteractionType) -> &'static str {
+    match interaction_type {
+        InteractionType::Touch => "touch",
+        InteractionType::Impact => "impact",
+        InteractionType::Stab => "stab",
+        InteractionType::Projectile => "projectile",
+        InteractionType::Grab => "grab",
+    }
+}
+
+pub fn muscle_name(muscle: Muscle) -> String {
+    format!("{:?}", muscle)
+}
+
+pub fn parse_muscle(name: &str) -> Option<Muscle> {
+    ALL.into_iter().find(|muscle| muscle_name(*muscle) == name)
+}
+
+// Fraction left after the elapsed time with the given time constant, instant when it is 0
+fn decay(elapsed_ms: f32, time_ms: u32) -> f32 {
+    if time_ms == 0 {
+        0.0
+    } else {
+        (-elapsed_ms / time_ms as f32).exp()
+    }
+}
+
+fn max_intensity() -> u8 {
+    MAX_INTENSITY
+}
+
+fn point_radius() -> f32 {
+    DEFAULT_POINT_RADIUS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(deadzone: f32, min_intensity: u8, max_intensity: u8) -> IntensityLimits {
+        IntensityLimits {
+            deadzone,
+            min_intensity,
+            max_intensity,
+        }
+    }
+
+    #[test]
+    fn deadzone_ignores_small_inputs_and_stretches_the_rest() {
+        let limits = limits(0.2, 0, MAX_INTENSITY);
+        assert_eq!(limits.input(0.1), None);
+        assert_eq!(limits.input(0.2), None);
+        assert!((limits.input(0.6).unwrap() - 0.5).abs() < 1e-6);
+        assert_eq!(limits.input(1.0), Some(1.0));
+    }
+
+    #[test]
+    fn minimum_only_lifts_intensities_that_are_sent() {
+        let limits = limits(0.0, 15, MAX_INTENSITY);
+        assert_eq!(limits.output(0.0, 1.0), 0);
+        assert_eq!(limits.output(3.0, 1.0), 15);
+        assert_eq!(limits.output(40.0, 1.0), 40);
+    }
+
+    #[test]
+    fn minimum_skips_parameters_without_intensity() {
+        let mut mapping = ParameterMapping::new("Pectoral_R", 0, 60, 100);
+        mapping.limits = limits(0.0, 15, MAX_INTENSITY);
+        let state = MuscleState {
+            depth: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(mapping.limits.output(mapping.intensity(&state), 1.0), 0);
+    }
+
+    #[test]
+    fn maximum_caps_the_output() {
+        let limits = limits(0.0, 0, 70);
+        assert_eq!(limits.output(69.6, 1.0), 70);
+        assert_eq!(limits.output(150.0, 1.0), 70);
+    }
+
+    #[test]
+    fn fading_goes_below_the_minimum() {
+        let limits = limits(0.0, 20, 60);
+        assert_eq!(limits.output(5.0, 0.5), 10);
+        assert_eq!(limits.output(100.0, 0.5), 30);
+        assert_eq!(limits.output(5.0, 0.0), 0);
+    }
+
+    #[test]
+    fn deadzone_applies_before_the_curve() {
+        let mut mapping = ParameterMapping::new("Pectoral_R", 50, 60, 100);
+        mapping.limits = limits(0.5, 0, MAX_INTENSITY);
+        let mut state = MuscleState {
+            depth: 0.4,
+            ..Default::default()
+        };
+        assert_eq!(mapping.intensity(&state), 0.0);
+        state.depth = 1.0;
+        assert_eq!(mapping.intensity(&state), 50.0);
+    }
+
+    fn approach(depths: &[(u64, f32)]) -> MuscleState {
+        let start = Instant::now();
+        let mut state = MuscleState::default();
+        for (ms, depth) in depths {
+            let time = start + Duration::from_millis(*ms);
+            state.record_depth(*depth, &Smoothing::default(), time);
+        }
+        state
+    }
+
+    #[test]
+    fn depth_velocity_fits_the_approach() {
+        let state = approach(&[(0, 0.0), (20, 0.1), (40, 0.2), (60, 0.3)]);
+        assert!((state.depth_velocity() - 5.0).abs() < 1e-3);
+        assert_eq!(approach(&[(0, 0.4)]).depth_velocity(), 0.0);
+        assert_eq!(approach(&[(0, 0.4), (20, 0.2)]).depth_velocity(), 0.0);
+        // Only the recent depths count
+        let state = approach(&[(0, 0.0), (100, 0.5), (300, 0.5), (320, 0.5)]);
+        assert_eq!(state.depth_velocity(), 0.0);
+    }
+
+    #[test]
+    fn estimate_impact_uses_the_depth_range() {
+        let range = DepthVelocityRange::default();
+        let mut state = approach(&[(0, 0.0), (20, 0.02), (40, 0.04)]);
+        state.estimate_impact(&range);
+        assert_eq!(state.interaction_type, InteractionType::Touch);
+
+        let mut state = approach(&[(0, 0.0), (20, 0.1), (40, 0.2)]);
+        state.estimate_impact(&range);
+        assert_eq!(state.interaction_type, InteractionType::Impact);
+        assert!((state.velocity - 0.5).abs() < 1e-3);
+
+        let range = DepthVelocityRange {
+            min: 6.0,
+            max: 20.0,
+        };
+        let mut state = approach(&[(0, 0.0), (20, 0.1), (40, 0.2)]);
+        state.estimate_impact(&range);
+        assert_eq!(state.interaction_type, InteractionType::Touch);
+        assert_eq!(state.velocity, 0.0);
+    }
+
+    #[test]
+    fn estimate_impact_keeps_the_fastest_velocity_of_one_approach() {
+        let range = DepthVelocityRange::default();
+        let start = Instant::now();
+        let mut state = MuscleState::default();
+        for (ms, depth) in [(0, 0.0), (20, 0.2), (40, 0.4), (60, 0.5), (80, 0.55)] {
+            let time = start + Duration::from_millis(ms);
+            state.record_depth(depth, &Smoothing::default(), time);
+            state.estimate_impact(&range);
+        }
+        assert_eq!(state.interaction_type, InteractionType::Impact);
+        assert!((state.velocity - 1.0).abs() < 1e-3);
+        assert!(state.approaching);
+    }
+
+    fn envelope_type(state: &MuscleState) -> Option<InteractionType> {
+        state
+            .envelope
+            .as_ref()
+            .map(|envelope| envelope.interaction_type)
+    }
+
+    #[test]
+    fn touches_are_grabs_while_grabbing_or_gripping() {
+        let envelopes = Envelopes::default();
+        let now = Instant::now();
+        let touch = || MuscleState {
+            depth: 0.5,
+            ..Default::default()
+        };
+
+        let mut state = touch();
+        state.update_envelope(&envelopes, false, now);
+        assert_eq!(envelope_type(&state), Some(InteractionType::Touch));
+        // Gripping during a touch starts the grab over
+        state.update_envelope(&envelopes, true, now + Duration::from_millis(50));
+        assert_eq!(envelope_type(&state), Some(InteractionType::Grab));
+        assert_eq!(
+            state.envelope.as_ref().unwrap().started,
+            now + Duration::from_millis(50)
+        );
+
+        let mut state = touch();
+        state.grabbing = true;
+        state.update_envelope(&envelopes, false, now);
+        assert_eq!(envelope_type(&state), Some(InteractionType::Grab));
+
+        // Letting go releases the grab
+        state.depth = 0.0;
+        state.update_envelope(&envelopes, false, now + Duration::from_millis(100));
+        let envelope = state.envelope.as_ref().unwrap();
+        assert_eq!(envelope.interaction_type, InteractionType::Grab);
+        assert_eq!(envelope.released, Some(now + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn grip_leaves_hits_alone() {
+        let envelopes = Envelopes::default();
+        let now = Instant::now();
+        let mut state = MuscleState {
+            depth: 0.5,
+            interaction_type: InteractionType::Impact,
+            velocity: 0.5,
+            ..Default::default()
+        };
+        state.update_envelope(&envelopes, true, now);
+        assert_eq!(envelope_type(&state), Some(InteractionType::Impact));
+        // The grab waits for the impact to finish
+        state.update_envelope(&envelopes, true, now + Duration::from_millis(50));
+        assert_eq!(envelope_type(&state), Some(InteractionType::Impact));
+    }
+
+    #[test]
+    fn rejects_invalid_limits() {
+        assert!(limits(0.0, 0, MAX_INTENSITY).validate().is_ok());
+        assert!(limits(1.0, 0, MAX_INTENSITY).validate().is_err());
+        assert!(limits(0.0, 0, MAX_INTENSITY + 1).validate().is_err());
+        assert!(limits(0.0, 50, 40).validate().is_err());
+    }
+
+    fn contacts() -> Vec<(InteractionType, Muscle, u8)> {
+        vec![
+            (InteractionType::Touch, Muscle::PectoralR, 30),
+            (InteractionType::Touch, Muscle::PectoralR, 10),
+            (InteractionType::Impact, Muscle::PectoralR, 60),
+            (InteractionType::Touch, Muscle::PectoralL, 20),
+            (InteractionType::Touch, Muscle::DorsalR, 50),
+            (InteractionType::Stab, Muscle::DorsalR, 50),
+        ]
+    }
+
+    fn mix(policy: MixPolicy) -> (Vec<InteractionType>, Vec<String>) {
+        let (interaction_types, muscles) = mix_interactions(contacts(), policy);
+        let packets = muscles
+            .into_iter()
+            .map(|(muscle, intensity)| MuscleWithIntensity::new(muscle, intensity).to_packet());
+        (interaction_types, packets.collect())
+    }
+
+    #[test]
+    fn per_muscle_max_plays_the_strongest_interaction_of_each_muscle() {
+        assert_eq!(
+            mix(MixPolicy::PerMuscleMax),
+            (
+                vec![
+                    InteractionType::Touch,
+                    InteractionType::Impact,
+                    InteractionType::Stab
+                ],
+                vec!["0%60".to_string(), "1%20".to_string(), "6%50".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn winner_takes_all_plays_only_the_highest_type() {
+        assert_eq!(
+            mix(MixPolicy::WinnerTakesAll),
+            (vec![InteractionType::Stab], vec!["6%50".to_string()])
+        );
+    }
+
+    #[test]
+    fn blend_adds_up_the_types_on_each_muscle() {
+        assert_eq!(
+            mix(MixPolicy::Blend),
+            (
+                vec![
+                    InteractionType::Touch,
+                    InteractionType::Impact,
+                    InteractionType::Stab
+                ],
+                vec!["0%90".to_string(), "1%20".to_string(), "6%100".to_string()]
+            )
+        );
+    }
+}
@@ -1,99 +1,262 @@
-use crate::muscle::{InteractionType, MuscleState};
-use vrchat_osc::rosc::{OscMessage, OscPacket, OscType};
+use crate::config::{apply_profile, Config};
+use crate::config_writer::request_save;
+use crate::muscle::{
+    find_parameter, split_parameter, InstanceMappings, InteractionType, MuscleState, MAX_SCALE,
+};
+use crate::owo_thread::Connection;
+use crate::smoothing::Smoothing;
 use std::collections::HashMap;
-use std::net::UdpSocket;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::sync::{Arc, Mutex};
-use vrchat_osc::models::{OscRootNode, OscValue};
+use std::time::{Duration, Instant};
+use vrchat_osc::models::{OscNode, OscRootNode, OscValue};
+use vrchat_osc::rosc::{OscMessage, OscPacket, OscType};
 use vrchat_osc::VRChatOSC;
 
-pub const PREFIX: &str = "/avatar/parameters/owo_pro/";
+pub const PARAMETER_PREFIX: &str = "/avatar/parameters/";
+// Parameter name of the prefab, used as the prefix of all its parameters
+pub const DEFAULT_PREFIX: &str = "owo_pro";
+pub const AVATAR_CHANGE: &str = "/avatar/change";
 pub const SEND_INTERVAL: u64 = 10;
+// How often the avatar is checked for changes to look up its velocity contacts
+pub const VELOCITY_DETECT_INTERVAL: Duration = Duration::from_secs(1);
+// Radial menu toggles, read from the avatar on start
+const TOGGLES: [&str; 7] = [
+    "velocity", "blade", "gun", "bullet", "hand", "grab", "chatbox",
+];
+// Settings that come back this close to the config are echoes, synced floats lose precision
+const SETTING_EPSILON: f32 = MAX_SCALE / 256.0;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn setup_osc_listener(
     contact_states: Arc<Mutex<HashMap<String, MuscleState>>>,
     toggle_states: Arc<Mutex<HashMap<String, bool>>>,
+    // Grip parameter of each prefix
+    grip_states: Arc<Mutex<HashMap<String, bool>>>,
+    config: Arc<Mutex<Config>>,
+    muscle_mappings: Arc<Mutex<Vec<InstanceMappings>>>,
+    connection: Arc<Mutex<Connection>>,
+    active_avatar: Arc<Mutex<Option<String>>>,
+    osc_ip: Option<IpAddr>,
+    osc_address: SocketAddr,
 ) -> Result<Arc<VRChatOSC>, Box<dyn std::error::Error>> {
-    let vrchat_osc = VRChatOSC::new(None).await?;
+    let vrchat_osc = VRChatOSC::new(osc_ip).await?;
+    let send_socket = create_send_socket()?;
+
+    // Fastest velocity contact of each prefix on the current avatar
+    let detected_velocities = Arc::new(Mutex::new(HashMap::<String, f32>::new()));
+    let detected_velocities_clone = detected_velocities.clone();
+    let active_avatar_clone = active_avatar.clone();
 
     let root_node = OscRootNode::new().with_avatar();
+    let config_clone = config.clone();
     let toggle_states_clone = toggle_states.clone();
+    let muscle_mappings_clone = muscle_mappings.clone();
+    let on_avatar_change = move |avatar_id: &str| {
+        change_avatar(
+            avatar_id,
+            &config,
+            &muscle_mappings_clone,
+            &connection,
+            &active_avatar,
+            &send_socket,
+            osc_address,
+        )
+    };
+    let on_avatar_change = Arc::new(on_avatar_change);
+    let on_avatar_change_clone = on_avatar_change.clone();
+    let muscle_mappings_clone = muscle_mappings.clone();
     vrchat_osc
         .register("owo_pro", root_node, move |packet| {
-            if let OscPacket::Message(msg) = packet {
-                if let Some(value) = msg.args.get(0) {
-                    if !msg.addr.starts_with(PREFIX) {
-                        return;
+            if let OscPacket::Message(msg) = packet
+                && let Some(value) = msg.args.first()
+            {
+                if msg.addr == AVATAR_CHANGE {
+                    if let OscType::String(avatar_id) = value {
+                        on_avatar_change_clone(avatar_id);
+                    } else {
+                        println!("Received non-string value for avatar change: {}", value);
                     }
+                    return;
+                }
 
-                    let param = &msg.addr[PREFIX.len()..];
+                let Some(param) = msg.addr.strip_prefix(PARAMETER_PREFIX) else {
+                    return;
+                };
+                let (prefix, velocity_source, velocity_range, depth_velocity_range, param) = {
+                    let instances = muscle_mappings_clone.lock().unwrap();
+                    let Some((instance, param)) = split_parameter(&instances, param) else {
+                        return;
+                    };
+                    (
+                        instance.prefix.clone(),
+                        instance.velocity_source,
+                        instance.velocity_range,
+                        instance.depth_velocity_range,
+                        param,
+                    )
+                };
 
-                    if param.starts_with("toggle/") {
-                        let (_, toggle_type) = param.split_once('/').unwrap();
-                        let mut toggle_states = toggle_states_clone.lock().unwrap();
-                        if let OscType::Bool(state) = value {
-                            toggle_states.insert(toggle_type.to_string(), *state);
-                            println!("Set toggle '{}' to {}", toggle_type, state);
+                // Floats from 0 to 1, like a radial puppet, covering the whole range of the scale
+                if let Some(setting) = param.strip_prefix("setting/") {
+                    if let OscType::Float(fraction) = value {
+                        let mut config = config_clone.lock().unwrap();
+                        let Some(scale) = config.intensity_scales.get_mut(setting) else {
+                            println!("Received unknown setting: {}", setting);
+                            return;
+                        };
+                        // The puppet echoes the values sent on avatar load, skip saving those
+                        let new_scale = fraction.clamp(0.0, 1.0) * MAX_SCALE;
+                        if (*scale - new_scale).abs() > SETTING_EPSILON {
+                            *scale = new_scale;
+                            config.revision += 1;
+                            // Saves are debounced, puppets send a stream of values while moved
+                            request_save(&config);
                         }
-                        return;
+                    } else {
+                        println!("Received non-float value for setting: {}", value);
                     }
+                    return;
+                }
 
-                    let (muscle, parameter) = param.split_once('/').unwrap();
+                if param.starts_with("toggle/") {
+                    let (_, toggle_type) = param.split_once('/').unwrap();
+                    let mut toggle_states = toggle_states_clone.lock().unwrap();
+                    if let OscType::Bool(state) = value {
+                        toggle_states.insert(toggle_type.to_string(), *state);
+                        println!("Set toggle '{}' to {}", toggle_type, state);
+                    }
+                    return;
+                }
 
-                    if parameter == "depth" {
-                        if let OscType::Float(depth) = value {
-                            let mut states = contact_states.lock().unwrap();
-                            if let Some(current_state) = states.get_mut(muscle) {
-                                current_state.depth = *depth;
-                            }
-                        } else {
-                            println!("Received non-float value for depth: {}", value);
-                        }
+                // Driven by the avatar, for example while a hand makes a fist. Every touch of the
+                // prefix is a grab while it is on.
+                if param == "grip" {
+                    if let OscType::Bool(state) = value {
+                        grip_states.lock().unwrap().insert(prefix.clone(), *state);
+                    } else {
+                        println!("Received non-bool value for grip: {}", value);
                     }
+                    return;
+                }
 
-                    if parameter.starts_with("velocity/") {
-                        let toggle_states = toggle_states_clone.lock().unwrap();
-                        let enabled = toggle_states.get("velocity").unwrap_or(&false);
-                        if !*enabled {
-                            return;
+                let Some((muscle, parameter)) = param.split_once('/') else {
+                    return;
+                };
+                // Contacts are tracked per instance, several prefabs may use the same muscle names.
+                // Instances added after startup get their state on the first message.
+                let muscle = format!("{}/{}", prefix, muscle);
+                let muscle = muscle.as_str();
+
+                if parameter == "depth" {
+                    if let OscType::Float(depth) = value {
+                        // Avatars without velocity contacts often lack the toggle too, so only an
+                        // explicit off disables the estimate
+                        let estimate_velocity = velocity_source.uses_depth()
+                            && *toggle_states_clone
+                                .lock()
+                                .unwrap()
+                                .get("velocity")
+                                .unwrap_or(&true);
+                        let smoothing =
+                            find_parameter(muscle, &muscle_mappings_clone.lock().unwrap())
+                                .map_or_else(Smoothing::default, |parameter| parameter.smoothing);
+                        let mut states = contact_states.lock().unwrap();
+                        let current_state = states.entry(muscle.to_string()).or_default();
+                        current_state.record_depth(*depth, &smoothing, Instant::now());
+                        if estimate_velocity {
+                            current_state.estimate_impact(&depth_velocity_range);
                         }
+                    } else {
+                        println!("Received non-float value for depth: {}", value);
+                    }
+                }
 
-                        let velocity = parameter[9..].parse::<f32>().unwrap();
-                        if let OscType::Bool(state) = value {
-                            let mut states = contact_states.lock().unwrap();
-                            if let Some(current_state) = states.get_mut(muscle) {
-                                if *state {
-                                    if current_state.velocity < velocity {
-                                        current_state.velocity = velocity;
-                                    }
-                                } else if current_state.interaction_type != InteractionType::Impact
-                                    && current_state.velocity > 0.0
-                                {
-                                    current_state.interaction_type = InteractionType::Impact;
-                                }
+                if let Some(velocity) = parameter.strip_prefix("velocity/") {
+                    if !velocity_source.uses_contacts() {
+                        return;
+                    }
+                    let enabled = *toggle_states_clone
+                        .lock()
+                        .unwrap()
+                        .get("velocity")
+                        .unwrap_or(&false);
+                    if !enabled {
+                        return;
+                    }
+
+                    let Ok(velocity) = velocity.parse::<f32>() else {
+                        println!("Received velocity contact with invalid speed: {}", velocity);
+                        return;
+                    };
+                    // A contact faster than the ones found on the avatar raises the maximum
+                    let detected_max = detected_velocities_clone
+                        .lock()
+                        .unwrap()
+                        .get_mut(&prefix)
+                        .map(|max| {
+                            *max = max.max(velocity);
+                            *max
+                        });
+                    let velocity = velocity_range.normalize(velocity, detected_max);
+                    if let OscType::Bool(state) = value {
+                        let mut states = contact_states.lock().unwrap();
+                        let current_state = states.entry(muscle.to_string()).or_default();
+                        if *state {
+                            if current_state.velocity < velocity {
+                                current_state.velocity = velocity;
                             }
-                        } else {
-                            println!("Received non-bool value for velocity: {}", value);
+                        } else if current_state.interaction_type != InteractionType::Impact
+                            && current_state.velocity > 0.0
+                        {
+                            current_state.interaction_type = InteractionType::Impact;
                         }
+                    } else {
+                        println!("Received non-bool value for velocity: {}", value);
                     }
+                }
 
-                    if parameter.starts_with("type/") {
-                        let (_, contact_type) = parameter.split_once('/').unwrap();
-                        let toggle_states = toggle_states_clone.lock().unwrap();
-                        let enabled = toggle_states.get(contact_type).unwrap_or(&false);
-                        if !*enabled {
+                if parameter.starts_with("type/") {
+                    let (_, contact_type) = parameter.split_once('/').unwrap();
+                    // Grabs last as long as the contact, so a grab must let go even once disabled
+                    if matches!(contact_type, "hand" | "grab") {
+                        let OscType::Bool(state) = value else {
+                            println!("Received non-bool value for {}: {}", contact_type, value);
                             return;
-                        }
+                        };
+                        let enabled = *toggle_states_clone
+                            .lock()
+                            .unwrap()
+                            .get(contact_type)
+                            .unwrap_or(&false);
+                        let mut states = contact_states.lock().unwrap();
+                        let current_state = states.entry(muscle.to_string()).or_default();
+                        current_state.grabbing = enabled && *state;
+                        return;
+                    }
 
-                        match contact_type {
-                            "blade" => {
-                                let mut states = contact_states.lock().unwrap();
-                                if let Some(current_state) = states.get_mut(muscle) {
-                                    current_state.interaction_type = InteractionType::Stab;
-                                }
-                            }
-                            _ => {
-                                println!("Received unknown contact type: {}", contact_type);
-                            }
+                    let enabled = *toggle_states_clone
+                        .lock()
+                        .unwrap()
+                        .get(contact_type)
+                        .unwrap_or(&false);
+                    if !enabled {
+                        return;
+                    }
+
+                    match contact_type {
+                        "blade" => {
+                            let mut states = contact_states.lock().unwrap();
+                            let current_state = states.entry(muscle.to_string()).or_default();
+                            current_state.interaction_type = InteractionType::Stab;
+                        }
+                        "gun" | "bullet" => {
+                            let mut states = contact_states.lock().unwrap();
+                            let current_state = states.entry(muscle.to_string()).or_default();
+                            current_state.interaction_type = InteractionType::Projectile;
+                        }
+                        _ => {
+                            println!("Received unknown contact type: {}", contact_type);
                         }
                     }
                 }
@@ -101,35 +264,189 @@ pub async fn setup_osc_listener(
         })
         .await?;
 
-    let mut toggle_states = toggle_states.lock().unwrap();
-    let toggles: Vec<String> = {
-        toggle_states.keys().cloned().collect()
-    };
-    for toggle in toggles {
-        let state = vrchat_osc.get_parameter("/avatar/parameters/owo_pro/", "VRChat-Client-*").await
+    // Toggles are shared by all instances, read them from the first one
+    let first_prefix = muscle_mappings
+        .lock()
+        .unwrap()
+        .first()
+        .map(|instance| instance.prefix.clone())
+        .unwrap_or_else(|| DEFAULT_PREFIX.to_string());
+    for toggle in TOGGLES {
+        let state = vrchat_osc
+            .get_parameter(
+                &format!("{}{}/toggle/{}", PARAMETER_PREFIX, first_prefix, toggle),
+                "VRChat-Client-*",
+            )
+            .await
             .ok()
-            .and_then(|state|
-                state.first()
+            .and_then(|state| {
+                state
+                    .first()
                     .and_then(|(_, node)| node.value.as_ref())
-                    .and_then(|vals| vals.get(0))
+                    .and_then(|vals| vals.first())
                     .and_then(|v| match v {
                         OscValue::Bool(state) => Some(*state),
                         _ => None,
                     })
-            )
-            .unwrap_or(false);
+            });
+
+        // Toggles the avatar lacks keep their default
+        if let Some(state) = state {
+            toggle_states
+                .lock()
+                .unwrap()
+                .insert(toggle.to_string(), state);
+        }
+    }
 
-        toggle_states.insert(toggle.to_string(), state);
+    // Pick up the avatar that was already loaded before we started
+    let avatar_id = vrchat_osc
+        .get_parameter(AVATAR_CHANGE, "VRChat-Client-*")
+        .await
+        .ok()
+        .and_then(|state| {
+            state
+                .first()
+                .and_then(|(_, node)| node.value.as_ref())
+                .and_then(|vals| vals.first())
+                .and_then(|v| match v {
+                    OscValue::String(avatar_id) => Some(avatar_id.clone()),
+                    _ => None,
+                })
+        });
+    if let Some(avatar_id) = avatar_id {
+        on_avatar_change(&avatar_id);
     }
 
+    // Prefab generator settings change the velocity contacts, look up the fastest ones whenever
+    // the avatar changes
+    let vrchat_osc_clone = vrchat_osc.clone();
+    tokio::spawn(async move {
+        let mut detected_avatar = None;
+        loop {
+            let avatar = active_avatar_clone.lock().unwrap().clone();
+            if avatar.is_some() && avatar != detected_avatar {
+                let velocities = detect_velocities(&vrchat_osc_clone).await;
+                for (prefix, velocity) in &velocities {
+                    println!("Fastest velocity contact for {} is {}", prefix, velocity);
+                }
+                *detected_velocities.lock().unwrap() = velocities;
+                detected_avatar = avatar;
+            }
+            tokio::time::sleep(VELOCITY_DETECT_INTERVAL).await;
+        }
+    });
+
     Ok(vrchat_osc)
 }
 
+async fn detect_velocities(vrchat_osc: &VRChatOSC) -> HashMap<String, f32> {
+    let mut velocities = HashMap::new();
+    let parameters = vrchat_osc
+        .get_parameter(PARAMETER_PREFIX.trim_end_matches('/'), "VRChat-Client-*")
+        .await
+        .unwrap_or_default();
+    if let Some((_, node)) = parameters.first() {
+        collect_velocities(node, &mut velocities);
+    }
+    velocities
+}
+
+// Parameters are named <prefix>/<parameter>/velocity/<N>, parameter names have no slashes
+fn collect_velocities(node: &OscNode, velocities: &mut HashMap<String, f32>) {
+    if let Some((contact, velocity)) = node
+        .full_path
+        .strip_prefix(PARAMETER_PREFIX)
+        .and_then(|parameter| parameter.rsplit_once("/velocity/"))
+        && let Some((prefix, _)) = contact.rsplit_once('/')
+        && let Ok(velocity) = velocity.parse::<f32>()
+    {
+        let max = velocities.entry(prefix.to_string()).or_insert(velocity);
+        *max = max.max(velocity);
+    }
+    for child in node.contents.values() {
+        collect_velocities(child, velocities);
+    }
+}
+
+fn change_avatar(
+    avatar_id: &str,
+    config: &Mutex<Config>,
+    muscle_mappings: &Mutex<Vec<InstanceMappings>>,
+    connection: &Mutex<Connection>,
+    active_avatar: &Mutex<Option<String>>,
+    send_socket: &UdpSocket,
+    osc_address: SocketAddr,
+) {
+    *active_avatar.lock().unwrap() = Some(avatar_id.to_string());
+
+    let mut config = config.lock().unwrap();
+    if let Some(profile) = &config.profile_override {
+        println!(
+            "Avatar changed to {}, keeping profile '{}' from the command line",
+            avatar_id, profile
+        );
+        send_settings(&config, send_socket, osc_address);
+        return;
+    }
+    let profile = config.profile_for_avatar(avatar_id).to_string();
+    println!(
+        "Avatar changed to {}, using profile '{}'",
+        avatar_id, profile
+    );
+    if config.active_profile != profile {
+        if let Err(e) = config.set_active_profile(&profile) {
+            println!("Error switching profile: {}", e);
+            return;
+        }
+        config.revision += 1;
+        apply_profile(config.active_profile(), muscle_mappings, connection);
+        request_save(&config);
+    }
+
+    send_settings(&config, send_socket, osc_address);
+}
+
+// Moves the radial puppets of every prefab to the current settings
+fn send_settings(config: &Config, socket: &UdpSocket, osc_address: SocketAddr) {
+    for instance in &config.active_profile().instances {
+        for (name, scale) in config.intensity_scales.values() {
+            let address = format!("{}{}/setting/{}", PARAMETER_PREFIX, instance.prefix, name);
+            let value = OscType::Float(scale / MAX_SCALE);
+            if let Err(e) = send_parameter(socket, osc_address, &address, value) {
+                println!("Error sending setting {}: {}", address, e);
+            }
+        }
+    }
+}
+
 pub fn create_send_socket() -> std::io::Result<UdpSocket> {
     UdpSocket::bind("0.0.0.0:0")
 }
 
-pub fn send_chatbox_message(socket: &UdpSocket, message: &str) -> std::io::Result<()> {
+pub fn send_parameter(
+    socket: &UdpSocket,
+    osc_address: SocketAddr,
+    address: &str,
+    value: OscType,
+) -> std::io::Result<()> {
+    socket.send_to(
+        vrchat_osc::rosc::encoder::encode(&OscPacket::Message(OscMessage {
+            addr: address.to_string(),
+            args: vec![value],
+        }))
+        .unwrap()
+        .as_slice(),
+        osc_address,
+    )?;
+    Ok(())
+}
+
+pub fn send_chatbox_message(
+    socket: &UdpSocket,
+    osc_address: SocketAddr,
+    message: &str,
+) -> std::io::Result<()> {
     socket.send_to(
         vrchat_osc::rosc::encoder::encode(&OscPacket::Message(OscMessage {
             addr: "/chatbox/input".to_string(),
@@ -141,7 +458,7 @@ pub fn send_chatbox_message(socket: &UdpSocket, message: &str) -> std::io::Resul
         }))
         .unwrap()
         .as_slice(),
-        ("127.0.0.1", 9000),
+        osc_address,
     )?;
     Ok(())
 }
\ No newline at end of file
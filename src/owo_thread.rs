@@ -1,36 +1,88 @@
-use crate::muscle::{get_intensity, get_muscle_for_parameter, InteractionType, MuscleState};
+use crate::config::Config;
+use crate::gesture::GestureRecognizer;
+use crate::muscle::{
+    find_parameter, get_intensity, get_muscles_for_parameter, merge_muscle_intensities,
+    mix_interactions, InstanceMappings, InteractionType, MuscleState,
+};
 use crate::osc::{create_send_socket, send_chatbox_message, SEND_INTERVAL};
 use owo_skin::auth::GameAuth;
 use owo_skin::client::Client;
-use owo_skin::muscles::Muscle;
 use owo_skin::sensation::Sensation;
 use std::cmp::max;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+pub struct Connection {
+    // Set when the connection settings changed and the OWO thread should reconnect
+    pub needs_connect: bool,
+    // IP address of the OWO application, searched for on the network if not set
+    pub ip_address: Option<String>,
+    // IP address from the command line, used instead of the profile's until the user connects
+    pub ip_override: Option<String>,
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self {
+            needs_connect: true,
+            ip_address: None,
+            ip_override: None,
+        }
+    }
+}
+
+impl Connection {
+    // Uses the IP address of a profile, reconnecting only if it differs from the current one
+    pub fn set_profile_ip_address(&mut self, ip_address: Option<String>) {
+        let ip_address = self
+            .ip_override
+            .clone()
+            .or(ip_address.filter(|ip| !ip.is_empty()));
+        if self.ip_address != ip_address {
+            self.ip_address = ip_address;
+            self.needs_connect = true;
+        }
+    }
+
+    // Connects to the given IP address, or searches the network if not set
+    pub fn connect(&mut self, ip_address: Option<String>) {
+        self.ip_override = None;
+        self.ip_address = ip_address;
+        self.needs_connect = true;
+    }
+}
 
 pub fn start_owo_thread(
     contact_states: Arc<Mutex<HashMap<String, MuscleState>>>,
-    needs_connect: Arc<Mutex<bool>>,
-    muscle_mappings: Arc<Mutex<[(&'static str, Muscle, u8, u8, u8); 10]>>,
+    connection: Arc<Mutex<Connection>>,
+    muscle_mappings: Arc<Mutex<Vec<InstanceMappings>>>,
     toggle_states: Arc<Mutex<HashMap<String, bool>>>,
-    ip_address: Arc<Mutex<Option<String>>>,
+    grip_states: Arc<Mutex<HashMap<String, bool>>>,
+    config: Arc<Mutex<Config>>,
+    osc_address: SocketAddr,
 ) {
     let send_socket = create_send_socket().expect("Failed to create send socket");
 
     thread::spawn(move || {
         let client = Client::new(GameAuth::default());
 
+        let mut recognizer = GestureRecognizer::default();
         let mut i = 0;
         loop {
             {
-                let needs_connect_state = *needs_connect.lock().unwrap();
-                if needs_connect_state {
+                let reconnect_ip = {
+                    let connection = connection.lock().unwrap();
+                    connection
+                        .needs_connect
+                        .then(|| connection.ip_address.clone())
+                };
+                if let Some(ip) = reconnect_ip {
                     println!("Connecting to OWO Application");
 
                     // Check if we have a specific IP to connect to
-                    let ip = ip_address.lock().unwrap().clone();
                     let success = if let Some(ip_str) = ip {
                         println!("Connecting to specific IP: {}", ip_str);
                         // Try to connect to the specific IP
@@ -44,92 +96,120 @@ pub fn start_owo_thread(
                         continue;
                     }
 
-                    *needs_connect.lock().unwrap() = false;
+                    connection.lock().unwrap().needs_connect = false;
                     println!("Connected to OWO Application");
                 }
 
-                // Create a list of active muscles
-                let mut states = contact_states.lock().unwrap();
-                let priority_type = states
-                    .iter()
-                    .map(|(_, state)| state.interaction_type)
-                    .max()
-                    .unwrap_or(InteractionType::Touch);
+                let (scales, envelopes, mix_policy, gestures) = {
+                    let config = config.lock().unwrap();
+                    (
+                        config.intensity_scales,
+                        config.envelopes,
+                        config.mix_policy,
+                        config.gestures.clone(),
+                    )
+                };
+                let grip_states = grip_states.lock().unwrap().clone();
+                let chatbox = *toggle_states
+                    .lock()
+                    .unwrap()
+                    .get("chatbox")
+                    .unwrap_or(&false);
 
+                // Contacts with a playing envelope, with the interaction to play and its level
+                let now = Instant::now();
+                let mut states = contact_states.lock().unwrap();
                 let mappings = &muscle_mappings.lock().unwrap();
-                let active_muscles = states
-                    .iter()
-                    .filter(|(_, state)| state.interaction_type == priority_type)
-                    .filter(|(_, state)| state.should_send_sensation())
-                    .filter_map(|(param, state)| get_muscle_for_parameter(param, state, mappings))
-                    .collect::<Vec<_>>();
+                let played_gestures = recognizer.update(&gestures, &mut states, mappings, now);
+                let playing: Vec<_> = states
+                    .iter_mut()
+                    .filter_map(|(param, state)| {
+                        // Contacts are named <prefix>/<parameter>, the grip is set per prefix
+                        let grip = param
+                            .rsplit_once('/')
+                            .and_then(|(prefix, _)| grip_states.get(prefix))
+                            .copied()
+                            .unwrap_or(false);
+                        state.update_envelope(&envelopes, grip, now);
+                        if let Some(parameter) = find_parameter(param, mappings) {
+                            state.update_adaptation(&parameter.adaptation, now);
+                        }
+                        let sensitivity = state.sensitivity;
+                        let (state, level) = state.playing(&envelopes, now)?;
+                        // Only touches and grabs last long enough to adapt to
+                        let sensitivity = if state.interaction_type.is_sustained() {
+                            sensitivity
+                        } else {
+                            1.0
+                        };
+                        let scale = scales.factor(state.interaction_type);
+                        Some((param, state, scale, level * sensitivity))
+                    })
+                    .collect();
+                // Resolve which interaction each muscle plays
+                let (interaction_types, muscles) = mix_interactions(
+                    playing.iter().flat_map(|(param, state, scale, fade)| {
+                        get_muscles_for_parameter(param, state, mappings, *scale, *fade)
+                            .into_iter()
+                            .map(|(muscle, intensity)| (state.interaction_type, muscle, intensity))
+                    }),
+                    mix_policy,
+                );
 
                 let mut highest_intensity = 0;
-                states.iter().for_each(|(param, state)| {
-                    if let Some(intensity) = get_intensity(param, state, mappings) {
+                playing.iter().for_each(|(param, state, scale, fade)| {
+                    if let Some(intensity) = get_intensity(param, state, mappings, *scale, *fade) {
                         highest_intensity = max(highest_intensity, intensity);
                     }
                 });
-                if priority_type != InteractionType::Touch {
-                    // Reset all states to touch
-                    states.iter_mut().for_each(|(_, state)| {
-                        state.interaction_type = InteractionType::Touch;
-                        state.velocity = 0.0;
-                    });
-                }
-
-                // Only send if there are active muscles
-                let sensation = match priority_type {
-                    InteractionType::Touch => Sensation::micro_sensation(
-                        100,
-                        0.3f32,
-                        100,
-                        0f32,
-                        0f32,
-                        0f32,
-                        "".to_string(),
-                    ),
-                    InteractionType::Impact => Sensation::micro_sensation(
-                        100,
-                        0.2f32,
-                        100,
-                        0f32,
-                        0f32,
-                        0f32,
-                        "".to_string(),
-                    ),
-                    InteractionType::Stab => Sensation::micro_sensation(
-                        60,
-                        0.3f32,
-                        100,
-                        0f32,
-                        0f32,
-                        0f32,
-                        "".to_string(),
-                    ),
-                };
 
                 i += 1;
-                if !active_muscles.is_empty() {
-                    let toggle_states = toggle_states.lock().unwrap();
-                    let enabled = toggle_states.get("chatbox").unwrap_or(&false);
-                    if *enabled
-                        && (i % SEND_INTERVAL == 0 || priority_type != InteractionType::Touch)
-                    {
+                // Only send if there are active muscles
+                if !interaction_types.is_empty() {
+                    let sustained_only = interaction_types
+                        .iter()
+                        .all(|interaction_type| interaction_type.is_sustained());
+                    if chatbox && (i % SEND_INTERVAL == 0 || !sustained_only) {
+                        let types = interaction_types
+                            .iter()
+                            .map(|interaction_type| format!("{:?}", interaction_type))
+                            .collect::<Vec<_>>()
+                            .join(", ");
                         let message = format!(
-                            "Type: {:#?}\nActive muscles: {}\nIntensity: {}",
-                            priority_type,
-                            active_muscles.len(),
+                            "Type: {}\nActive muscles: {}\nIntensity: {}",
+                            types,
+                            muscles.len(),
                             highest_intensity
                         );
                         println!("{}", message);
 
-                        if let Err(e) = send_chatbox_message(&send_socket, &message) {
+                        if let Err(e) = send_chatbox_message(&send_socket, osc_address, &message) {
                             println!("Error sending chatbox message: {}", e);
                         }
                     }
+                }
 
-                    client.send_sensation(Sensation::with_muscles(sensation, active_muscles));
+                // The app plays one sensation at a time, so each tick sends a single one. The
+                // highest interaction type sets how it feels, and a gesture plays on top of the
+                // contacts that make it.
+                let gesture = played_gestures.first();
+                if let Some(gesture) = gesture {
+                    println!("Playing gesture '{}'", gesture.name);
+                }
+                let sensation = match gesture {
+                    Some(gesture) => Some(gesture.sensation.sensation()),
+                    None => interaction_types
+                        .last()
+                        .map(|interaction_type| interaction_sensation(*interaction_type)),
+                };
+                if let Some(sensation) = sensation {
+                    let gesture_muscles = gesture
+                        .map(|gesture| gesture.muscle_intensities(scales.master, mappings))
+                        .unwrap_or_default();
+                    client.send_sensation(Sensation::with_muscles(
+                        sensation,
+                        merge_muscle_intensities(muscles.into_iter().chain(gesture_muscles)),
+                    ));
                 }
             }
 
@@ -137,3 +217,25 @@ pub fn start_owo_thread(
         }
     });
 }
+
+fn interaction_sensation(interaction_type: InteractionType) -> Sensation {
+    match interaction_type {
+        InteractionType::Touch => {
+            Sensation::micro_sensation(100, 0.3f32, 100, 0f32, 0f32, 0f32, "".to_string())
+        }
+        InteractionType::Impact => {
+            Sensation::micro_sensation(100, 0.2f32, 100, 0f32, 0f32, 0f32, "".to_string())
+        }
+        InteractionType::Stab => {
+            Sensation::micro_sensation(60, 0.3f32, 100, 0f32, 0f32, 0f32, "".to_string())
+        }
+        // Low and long, so the squeeze blends into one sustained pressure
+        InteractionType::Grab => {
+            Sensation::micro_sensation(30, 0.4f32, 100, 0f32, 0f32, 0f32, "".to_string())
+        }
+        // Sharp and short, a single shot should not blur into the next one
+        InteractionType::Projectile => {
+            Sensation::micro_sensation(100, 0.1f32, 100, 0f32, 0f32, 0f32, "".to_string())
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use crate::config::{
+    migrate_instance_muscles_to_parameters, ConfigError, InstanceConfig, Profile, ProfileMetadata,
+};
+use crate::muscle::{DepthVelocityRange, VelocityRange};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+pub const PROFILE_FILE_FORMAT: &str = "vrc-owo-profile";
+pub const PROFILE_FILE_VERSION: u32 = 2;
+
+// A single profile as it is shared with others, without the connection settings of this machine
+#[derive(Serialize, Deserialize)]
+pub struct ProfileFile {
+    pub format: String,
+    pub version: u32,
+    #[serde(default)]
+    pub metadata: ProfileMetadata,
+    pub profile: SharedProfile,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SharedProfile {
+    pub name: String,
+    pub instances: Vec<InstanceConfig>,
+    #[serde(default)]
+    pub velocity_range: VelocityRange,
+    #[serde(default)]
+    pub depth_velocity_range: DepthVelocityRange,
+}
+
+// Writes TOML for .toml files and JSON for everything else
+pub fn export_profile(profile: &Profile, path: &Path) -> Result<(), ConfigError> {
+    let file = ProfileFile {
+        format: PROFILE_FILE_FORMAT.to_string(),
+        version: PROFILE_FILE_VERSION,
+        metadata: profile.metadata.clone(),
+        profile: SharedProfile {
+            name: profile.name.clone(),
+            instances: profile.instances.clone(),
+            velocity_range: profile.velocity_range,
+            depth_velocity_range: profile.depth_velocity_range,
+        },
+    };
+
+    let contents = if is_toml(path) {
+        toml::to_string_pretty(&file).map_err(|e| ConfigError::InvalidProfileFile(e.to_string()))?
+    } else {
+        serde_json::to_string_pretty(&file)
+            .map_err(|e| ConfigError::InvalidProfileFile(e.to_string()))?
+    };
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+pub fn import_profile(path: &Path) -> Result<Profile, ConfigError> {
+    let contents = fs::read_to_string(path)?;
+    let mut value: Value = if is_toml(path) {
+        toml::from_str(&contents).map_err(|e| toml_error(&contents, e))?
+    } else {
+        serde_json::from_str(&contents)
+            .map_err(|e| ConfigError::InvalidProfileFile(e.to_string()))?
+    };
+
+    let format = value
+        .get("format")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    if format != PROFILE_FILE_FORMAT {
+        return Err(ConfigError::InvalidProfileFile(format!(
+            "expected format '{}', found '{}'",
+            PROFILE_FILE_FORMAT, format
+        )));
+    }
+    let version = value.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version > PROFILE_FILE_VERSION as u64 {
+        return Err(ConfigError::InvalidProfileFile(format!(
+            "version {} is newer than supported version {}",
+            version, PROFILE_FILE_VERSION
+        )));
+    }
+    // Version 1 mapped each parameter to a single muscle
+    if version < 2
+        && let Some(instances) = value
+            .pointer_mut("/profile/instances")
+            .and_then(Value::as_array_mut)
+    {
+        instances
+            .iter_mut()
+            .for_each(migrate_instance_muscles_to_parameters);
+    }
+    let file: ProfileFile = serde_json::from_value(value)
+        .map_err(|e| ConfigError::InvalidProfileFile(e.to_string()))?;
+
+    if file.profile.instances.is_empty() {
+        return Err(ConfigError::InvalidProfileFile(
+            "the profile has no parameter prefixes".to_string(),
+        ));
+    }
+
+    let profile = Profile {
+        name: file.profile.name,
+        instances: file.profile.instances,
+        ip_address: None,
+        metadata: file.metadata,
+        velocity_range: file.profile.velocity_range,
+        depth_velocity_range: file.profile.depth_velocity_range,
+    };
+    profile.validate()?;
+    Ok(profile)
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("toml"))
+}
+
+// The TOML error message spans several lines with a snippet of the file, report only the position
+fn toml_error(contents: &str, e: toml::de::Error) -> ConfigError {
+    let position = e.span().map(|span| {
+        let before = &contents[..span.start];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        (
+            before.matches('\n').count() + 1,
+            before.len() - line_start + 1,
+        )
+    });
+    ConfigError::InvalidProfileFile(match position {
+        Some((line, column)) => format!("line {}, column {}: {}", line, column, e.message()),
+        None => e.message().to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::muscle::default_muscle_mapping;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vrc-owo-{}-{}", std::process::id(), name))
+    }
+
+    fn shared_profile() -> Profile {
+        let mut profile = Config::default().active_profile().clone();
+        profile.name = "Shared".to_string();
+        profile.ip_address = Some("192.168.1.20".to_string());
+        profile.metadata.author = "Someone".to_string();
+        profile.instances[0].parameters[0].intensity_touch = 33;
+        profile
+    }
+
+    fn round_trip(file_name: &str) {
+        let path = temp_path(file_name);
+        let profile = shared_profile();
+        export_profile(&profile, &path).unwrap();
+        let imported = import_profile(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert_eq!(imported.name, "Shared");
+        assert_eq!(imported.metadata.author, "Someone");
+        // The connection settings stay on this machine
+        assert_eq!(imported.ip_address, None);
+        assert_eq!(
+            serde_json::to_value(&imported.instances).unwrap(),
+            serde_json::to_value(&profile.instances).unwrap()
+        );
+    }
+
+    fn import_json(name: &str, file: serde_json::Value) -> Result<Profile, ConfigError> {
+        let path = temp_path(name);
+        fs::write(&path, file.to_string()).unwrap();
+        let result = import_profile(&path);
+        fs::remove_file(&path).ok();
+        result
+    }
+
+    fn exported_json() -> serde_json::Value {
+        let path = temp_path("exported.json");
+        export_profile(&shared_profile(), &path).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).ok();
+        serde_json::from_str(&contents).unwrap()
+    }
+
+    #[test]
+    fn json_round_trips() {
+        round_trip("round_trip.json");
+    }
+
+    #[test]
+    fn toml_round_trips() {
+        round_trip("round_trip.toml");
+    }
+
+    #[test]
+    fn rejects_other_formats_and_newer_versions() {
+        let mut file = exported_json();
+        file["format"] = "something-else".into();
+        assert!(matches!(
+            import_json("format.json", file),
+            Err(ConfigError::InvalidProfileFile(_))
+        ));
+
+        let mut file = exported_json();
+        file["version"] = (PROFILE_FILE_VERSION + 1).into();
+        assert!(matches!(
+            import_json("version.json", file),
+            Err(ConfigError::InvalidProfileFile(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_muscles() {
+        let mut file = exported_json();
+        file["profile"]["instances"][0]["parameters"][0]["muscles"][0]["muscle"] = "Elbow".into();
+        assert!(matches!(
+            import_json("muscle.json", file),
+            Err(ConfigError::UnknownMuscle { .. })
+        ));
+    }
+
+    #[test]
+    fn migrates_version_1_muscles_to_parameters() {
+        let file = serde_json::json!({
+            "format": PROFILE_FILE_FORMAT,
+            "version": 1,
+            "profile": {
+                "name": "Old",
+                "instances": [{
+                    "prefix": "owo_pro",
+                    "muscles": [{
+                        "name": "Pectoral_R",
+                        "muscle": "PectoralL",
+                        "intensity_touch": 33,
+                        "intensity_impact": 44,
+                        "intensity_stab": 55
+                    }]
+                }]
+            }
+        });
+        let profile = import_json("version_1.json", file).unwrap();
+        let parameters = &profile.instances[0].parameters;
+        let parameter = parameters
+            .iter()
+            .find(|parameter| parameter.name == "Pectoral_R")
+            .unwrap();
+        assert_eq!(parameter.intensity_touch, 33);
+        let [link] = parameter.muscles.as_slice() else {
+            panic!("expected one link, found {}", parameter.muscles.len());
+        };
+        assert_eq!((link.muscle.as_str(), link.weight), ("PectoralL", 1.0));
+        // Parameters the file left out used to fall back to the defaults
+        assert_eq!(parameters.len(), default_muscle_mapping().parameters.len());
+    }
+}
@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::time::Instant;
+
+// Cutoff of the speed estimate of the one euro filter, in Hz
+const DERIVATIVE_CUTOFF: f32 = 1.0;
+
+// Starting values when a filter is picked
+pub const DEFAULT_EMA_FACTOR: f32 = 0.5;
+pub const DEFAULT_MIN_CUTOFF: f32 = 1.0;
+pub const DEFAULT_BETA: f32 = 0.5;
+
+// Filters the jitter of contact depths when avatars are close
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DepthFilter {
+    #[default]
+    None,
+    // Moves this fraction of the way to each new depth, lower values smooth more
+    Ema {
+        factor: f32,
+    },
+    // Smooths a contact that stays more than one that moves. The cutoff in Hz is used while the
+    // contact stays and grows by beta with its speed in depth per second.
+    OneEuro {
+        min_cutoff: f32,
+        beta: f32,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Smoothing {
+    pub filter: DepthFilter,
+    // Rises larger than this skip the filter, so impacts keep their peak
+    pub peak_threshold: f32,
+}
+
+// What a filter remembers between two depths of a contact
+#[derive(Clone, Default, Debug)]
+pub struct FilterState {
+    value: f32,
+    speed: f32,
+    time: Option<Instant>,
+}
+
+impl Default for Smoothing {
+    fn default() -> Self {
+        Self {
+            filter: DepthFilter::None,
+            peak_threshold: 0.2,
+        }
+    }
+}
+
+impl Smoothing {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.peak_threshold) {
+            return Err(format!(
+                "peak threshold must be between 0 and 1, found {}",
+                self.peak_threshold
+            ));
+        }
+        match self.filter {
+            DepthFilter::None => Ok(()),
+            DepthFilter::Ema { factor } => {
+                if factor > 0.0 && factor <= 1.0 {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "factor must be above 0 and at most 1, found {}",
+                        factor
+                    ))
+                }
+            }
+            DepthFilter::OneEuro { min_cutoff, beta } => {
+                if !(min_cutoff.is_finite() && min_cutoff > 0.0) {
+                    Err(format!("cutoff must be above 0, found {}", min_cutoff))
+                } else if !(beta.is_finite() && beta >= 0.0) {
+                    Err(format!("beta must be at least 0, found {}", beta))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+impl FilterState {
+    pub fn filter(&mut self, smoothing: &Smoothing, depth: f32, now: Instant) -> f32 {
+        let previous = self.time.replace(now);
+        // A contact that ends or hits hard is passed on right away
+        let Some(previous) = previous.filter(|_| {
+            depth > 0.0 && self.value > 0.0 && depth - self.value <= smoothing.peak_threshold
+        }) else {
+            self.value = depth;
+            self.speed = 0.0;
+            return depth;
+        };
+
+        match smoothing.filter {
+            DepthFilter::None => self.value = depth,
+            DepthFilter::Ema { factor } => self.value += (depth - self.value) * factor,
+            DepthFilter::OneEuro { min_cutoff, beta } => {
+                let elapsed = now.duration_since(previous).as_secs_f32();
+                if elapsed <= 0.0 {
+                    return self.value;
+                }
+                let speed = (depth - self.value) / elapsed;
+                self.speed += (speed - self.speed) * smoothing_factor(elapsed, DERIVATIVE_CUTOFF);
+                let cutoff = min_cutoff + beta * self.speed.abs();
+                self.value += (depth - self.value) * smoothing_factor(elapsed, cutoff);
+            }
+        }
+        self.value
+    }
+}
+
+// Fraction of the way to move toward a new value for a low pass with the given cutoff
+fn smoothing_factor(elapsed: f32, cutoff: f32) -> f32 {
+    let time_constant = 1.0 / (2.0 * PI * cutoff);
+    1.0 / (1.0 + time_constant / elapsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const ONE_EURO: Smoothing = Smoothing {
+        filter: DepthFilter::OneEuro {
+            min_cutoff: DEFAULT_MIN_CUTOFF,
+            beta: DEFAULT_BETA,
+        },
+        peak_threshold: 0.2,
+    };
+
+    #[test]
+    fn one_euro_passes_peaks_through() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut state = FilterState::default();
+        assert_eq!(state.filter(&ONE_EURO, 0.1, at(0)), 0.1);
+        assert_eq!(state.filter(&ONE_EURO, 0.8, at(20)), 0.8);
+
+        let smoothed = state.filter(&ONE_EURO, 0.85, at(40));
+        assert!(smoothed > 0.8 && smoothed < 0.85, "{}", smoothed);
+        let smoothed = state.filter(&ONE_EURO, 0.75, at(60));
+        assert!(smoothed > 0.75 && smoothed < 0.85, "{}", smoothed);
+    }
+
+    #[test]
+    fn contacts_end_right_away() {
+        let start = Instant::now();
+        let mut state = FilterState::default();
+        state.filter(&ONE_EURO, 0.5, start);
+        assert_eq!(
+            state.filter(&ONE_EURO, 0.0, start + Duration::from_millis(20)),
+            0.0
+        );
+        assert_eq!(
+            state.filter(&ONE_EURO, 0.3, start + Duration::from_millis(40)),
+            0.3
+        );
+    }
+
+    #[test]
+    fn ema_moves_part_of_the_way() {
+        let smoothing = Smoothing {
+            filter: DepthFilter::Ema { factor: 0.5 },
+            ..Smoothing::default()
+        };
+        let now = Instant::now();
+        let mut state = FilterState::default();
+        state.filter(&smoothing, 0.4, now);
+        assert_eq!(state.filter(&smoothing, 0.5, now), 0.45);
+        assert_eq!(state.filter(&smoothing, 0.9, now), 0.9);
+    }
+}
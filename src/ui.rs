@@ -1,99 +1,817 @@
-use crate::config::{Config, MuscleConfig, save_config};
-use slint::Model;
+use crate::config::{apply_profile, restore_previous_config, Config, ConfigError, InstanceConfig};
+use crate::config_writer::{flush_saves, request_save};
+use crate::curve::ResponseCurve;
+use crate::envelope::Envelope;
+use crate::muscle::{
+    interaction_name, muscle_name, Adaptation, DepthVelocityRange, InstanceMappings,
+    IntensityLimits, IntensityScales, InteractionType, MixPolicy, VelocityRange, VelocitySource,
+    BODY_POINTS,
+};
+use crate::owo_thread::Connection;
+use crate::profile_file::{export_profile, import_profile};
+use crate::smoothing::{
+    DepthFilter, Smoothing, DEFAULT_BETA, DEFAULT_EMA_FACTOR, DEFAULT_MIN_CUTOFF,
+};
+use owo_skin::muscles::ALL;
+use slint::{Model, ModelRc, SharedString, Timer, TimerMode, VecModel};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use owo_skin::muscles::Muscle;
+use std::time::Duration;
 
 slint::include_modules!();
 
 pub fn setup_ui(
-    muscle_mappings: Arc<Mutex<[(&'static str, Muscle, u8, u8, u8); 10]>>,
-    needs_connect: Arc<Mutex<bool>>,
-    ip_address: Arc<Mutex<Option<String>>>,
+    config: Arc<Mutex<Config>>,
+    muscle_mappings: Arc<Mutex<Vec<InstanceMappings>>>,
+    connection: Arc<Mutex<Connection>>,
+    active_avatar: Arc<Mutex<Option<String>>>,
+    config_warning: Arc<Mutex<Option<String>>>,
 ) -> Result<(), std::io::Error> {
     let app = App::new().unwrap();
 
-    // Load IP address from config if available
-    if let Some(config) = crate::config::load_config() {
-        if let Some(ip) = config.ip_address {
-            app.set_ip_address(ip.into());
-        }
-    }
+    let muscle_names = ALL
+        .into_iter()
+        .map(|muscle| SharedString::from(muscle_name(muscle)))
+        .collect::<Vec<_>>();
+    app.set_muscle_names(ModelRc::new(VecModel::from(muscle_names)));
+    let body_points = BODY_POINTS
+        .iter()
+        .map(|(name, _)| SharedString::from(*name))
+        .collect::<Vec<_>>();
+    app.set_body_points(ModelRc::new(VecModel::from(body_points)));
+    show_profile(
+        &app,
+        &config.lock().unwrap(),
+        &muscle_mappings.lock().unwrap(),
+    );
 
-    {
-        let mappings = muscle_mappings.lock().unwrap();
-        app.set_muscles(
-            mappings
-                .iter()
-                .map(
-                    |(name, _, intensity_touch, intensity_impact, intensity_stab)| MuscleData {
-                        name: name.to_string().into(),
-                        intensities: MuscleIntensities {
-                            touch: *intensity_touch as i32,
-                            impact: *intensity_impact as i32,
-                            stab: *intensity_stab as i32,
-                        },
-                    },
-                )
-                .collect::<Vec<MuscleData>>()
-                .as_slice()
-                .into(),
-        );
-    }
     let app_handle = app.as_weak();
+    let config_clone = config.clone();
+    let muscle_mappings_clone = muscle_mappings.clone();
+    let connection_clone = connection.clone();
     app.on_update(move || {
         let app = app_handle.unwrap();
-        let mut mappings = muscle_mappings.lock().unwrap();
+        let prefix = app.get_active_instance();
+        let mut config = config_clone.lock().unwrap();
+        let Some(instance) = config.active_profile_mut().instance_mut(&prefix) else {
+            return;
+        };
         app.get_muscles().iter().for_each(|muscle| {
-            if let Some((_, _, intensity_touch, intensity_impact, intensity_stab)) = mappings
-                .iter_mut()
-                .find(|(name, _, _, _, _)| *name == muscle.name.as_str())
-            {
-                *intensity_touch = muscle.intensities.touch as u8;
-                *intensity_impact = muscle.intensities.impact as u8;
-                *intensity_stab = muscle.intensities.stab as u8;
+            if let Some(parameter) = instance.parameter_mut(muscle.name.as_str()) {
+                parameter.intensity_touch = muscle.intensities.touch as u8;
+                parameter.intensity_impact = muscle.intensities.impact as u8;
+                parameter.intensity_stab = muscle.intensities.stab as u8;
+                parameter.intensity_projectile = muscle.intensities.projectile as u8;
+                parameter.intensity_grab = muscle.intensities.grab as u8;
+                parameter.limits = IntensityLimits {
+                    deadzone: muscle.limits.deadzone as f32 / 100.0,
+                    min_intensity: muscle.limits.min as u8,
+                    max_intensity: muscle.limits.max as u8,
+                };
+                parameter.adaptation = Adaptation {
+                    enabled: muscle.adaptation.enabled,
+                    floor: muscle.adaptation.floor as f32 / 100.0,
+                    fade_ms: muscle.adaptation.fade as u32 * 1000,
+                    recover_ms: muscle.adaptation.recover as u32,
+                    ..parameter.adaptation
+                };
+                parameter.smoothing = parse_smoothing(&muscle.smoothing);
             }
         });
 
         // Save config after update
-        let config = Config {
-            muscles: mappings
-                .iter()
-                .map(
-                    |(name, muscle, intensity_touch, intensity_impact, intensity_stab)| {
-                        MuscleConfig {
-                            name: name.to_string(),
-                            muscle: format!("{:?}", muscle),
-                            intensity_touch: *intensity_touch,
-                            intensity_impact: *intensity_impact,
-                            intensity_stab: *intensity_stab,
-                        }
-                    },
-                )
-                .collect(),
-            ip_address: Some(app.get_ip_address().to_string()),
+        apply_profile(
+            config.active_profile(),
+            &muscle_mappings_clone,
+            &connection_clone,
+        );
+        save(&config);
+    });
+
+    let app_handle = app.as_weak();
+    let config_clone = config.clone();
+    app.on_update_scales(move || {
+        let app = app_handle.unwrap();
+        let mut config = config_clone.lock().unwrap();
+        config.intensity_scales = IntensityScales {
+            master: app.get_scale_master() as f32 / 100.0,
+            touch: app.get_scale_touch() as f32 / 100.0,
+            impact: app.get_scale_impact() as f32 / 100.0,
+            stab: app.get_scale_stab() as f32 / 100.0,
+            projectile: app.get_scale_projectile() as f32 / 100.0,
+            grab: app.get_scale_grab() as f32 / 100.0,
         };
-        if let Err(e) = save_config(&config) {
-            println!("Error saving config: {}", e);
+        save(&config);
+    });
+
+    let config_clone = config.clone();
+    app.on_set_mix_policy(move |policy| {
+        let mut config = config_clone.lock().unwrap();
+        config.mix_policy = parse_mix_policy(&policy);
+        save(&config);
+    });
+
+    let app_handle = app.as_weak();
+    let config_clone = config.clone();
+    app.on_load_envelope(move |interaction| {
+        let app = app_handle.unwrap();
+        let envelope = *config_clone
+            .lock()
+            .unwrap()
+            .envelopes
+            .get(parse_interaction(&interaction));
+        show_envelope(&app, &envelope);
+    });
+
+    let app_handle = app.as_weak();
+    let config_clone = config.clone();
+    app.on_apply_envelope(move |interaction, attack, decay, sustain, hold, release| {
+        let app = app_handle.unwrap();
+        let envelope = Envelope {
+            attack_ms: attack as u32,
+            decay_ms: decay as u32,
+            sustain: sustain as f32 / 100.0,
+            hold_ms: hold as u32,
+            release_ms: release as u32,
+        };
+        let mut config = config_clone.lock().unwrap();
+        if let Err(e) = config.set_envelope(parse_interaction(&interaction), envelope) {
+            app.set_config_warning(e.to_string().into());
+            return;
         }
+        save(&config);
     });
 
-    let ip_address_clone = ip_address.clone();
-    let needs_connect_clone = needs_connect.clone();
+    let config_clone = config.clone();
+    app.on_set_gesture_enabled(move |name, enabled| {
+        let mut config = config_clone.lock().unwrap();
+        if let Some(gesture) = config
+            .gestures
+            .iter_mut()
+            .find(|gesture| gesture.name == name.as_str())
+        {
+            gesture.enabled = enabled;
+            save(&config);
+        }
+    });
+
+    let config_clone = config.clone();
+    let connection_clone = connection.clone();
     app.on_connect(move || {
-        let mut ip_addr = ip_address_clone.lock().unwrap();
-        *ip_addr = None;
+        {
+            let mut config = config_clone.lock().unwrap();
+            config.active_profile_mut().ip_address = None;
+            save(&config);
+        }
 
-        *needs_connect_clone.lock().unwrap() = true;
+        connection_clone.lock().unwrap().connect(None);
     });
 
+    let config_clone = config.clone();
+    let connection_clone = connection.clone();
     app.on_connect_ip(move |ip| {
-        let mut ip_addr = ip_address.lock().unwrap();
-        *ip_addr = Some(ip.to_string());
+        {
+            let mut config = config_clone.lock().unwrap();
+            config.active_profile_mut().ip_address = Some(ip.to_string());
+            save(&config);
+        }
+
+        connection_clone
+            .lock()
+            .unwrap()
+            .connect(Some(ip.to_string()));
+    });
+
+    let on_profile_change = {
+        let app_handle = app.as_weak();
+        let config = config.clone();
+        let muscle_mappings = muscle_mappings.clone();
+        move |change: &dyn Fn(&mut Config) -> Result<(), ConfigError>| {
+            let app = app_handle.unwrap();
+            let mut config = config.lock().unwrap();
+            if let Err(e) = change(&mut config) {
+                app.set_config_warning(e.to_string().into());
+                return;
+            }
+
+            apply_profile(config.active_profile(), &muscle_mappings, &connection);
+            show_profile(&app, &config, &muscle_mappings.lock().unwrap());
+            save(&config);
+        }
+    };
+    let on_profile_change = Arc::new(on_profile_change);
+
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_select_profile(move |name| {
+        on_profile_change_clone(&|config| config.set_active_profile(&name));
+    });
+
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_new_profile(move |name| {
+        on_profile_change_clone(&|config| {
+            config.create_profile(&name)?;
+            config.set_active_profile(&name)
+        });
+    });
+
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_clone_profile(move |name| {
+        on_profile_change_clone(&|config| {
+            let source = config.active_profile_name().to_string();
+            config.clone_profile(&source, &name)?;
+            config.set_active_profile(&name)
+        });
+    });
+
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_rename_profile(move |name| {
+        on_profile_change_clone(&|config| {
+            let old_name = config.active_profile_name().to_string();
+            config.rename_profile(&old_name, &name)
+        });
+    });
+
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_delete_profile(move || {
+        on_profile_change_clone(&|config| {
+            let name = config.active_profile_name().to_string();
+            config.delete_profile(&name)
+        });
+    });
+
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_set_default_profile(move || {
+        on_profile_change_clone(&|config| {
+            let name = config.active_profile_name().to_string();
+            config.set_default_profile(&name)
+        });
+    });
+
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_restore_previous_config(move || {
+        // Make sure the previous config is the one before the latest edits
+        flush_saves();
+        on_profile_change_clone(&|config| {
+            let mut previous = restore_previous_config()?;
+            previous.revision = config.revision + 1;
+            *config = previous;
+            Ok(())
+        });
+    });
+
+    let app_handle = app.as_weak();
+    let config_clone = config.clone();
+    let muscle_mappings_clone = muscle_mappings.clone();
+    app.on_select_instance(move |prefix| {
+        let app = app_handle.unwrap();
+        app.set_active_instance(prefix);
+        show_profile(
+            &app,
+            &config_clone.lock().unwrap(),
+            &muscle_mappings_clone.lock().unwrap(),
+        );
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_add_instance(move |prefix| {
+        let app = app_handle.unwrap();
+        on_profile_change_clone(&|config| {
+            config.active_profile_mut().add_instance(&prefix)?;
+            app.set_active_instance(prefix.clone());
+            Ok(())
+        });
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_rename_instance(move |prefix| {
+        let app = app_handle.unwrap();
+        let old_prefix = app.get_active_instance();
+        on_profile_change_clone(&|config| {
+            config
+                .active_profile_mut()
+                .rename_instance(&old_prefix, &prefix)?;
+            app.set_active_instance(prefix.clone());
+            Ok(())
+        });
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_remove_instance(move || {
+        let prefix = app_handle.unwrap().get_active_instance();
+        on_profile_change_clone(&|config| config.active_profile_mut().remove_instance(&prefix));
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_apply_velocity_range(move |min, max, clamp, auto_detect| {
+        let app = app_handle.unwrap();
+        let (Ok(min), Ok(max)) = (min.trim().parse::<f32>(), max.trim().parse::<f32>()) else {
+            app.set_config_warning("The velocity range must be numbers".into());
+            return;
+        };
+        let velocity_range = VelocityRange {
+            min,
+            max,
+            clamp,
+            auto_detect,
+        };
+        on_profile_change_clone(&|config| {
+            config
+                .active_profile_mut()
+                .set_velocity_range(velocity_range)
+        });
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_apply_depth_velocity_range(move |min, max| {
+        let app = app_handle.unwrap();
+        let (Ok(min), Ok(max)) = (min.trim().parse::<f32>(), max.trim().parse::<f32>()) else {
+            app.set_config_warning("The depth velocity range must be numbers".into());
+            return;
+        };
+        let range = DepthVelocityRange { min, max };
+        on_profile_change_clone(&|config| {
+            config.active_profile_mut().set_depth_velocity_range(range)
+        });
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_set_velocity_source(move |source| {
+        let prefix = app_handle.unwrap().get_active_instance();
+        let source = parse_velocity_source(&source);
+        on_profile_change_clone(&|config| {
+            active_instance_mut(config, &prefix)?.velocity_source = source;
+            Ok(())
+        });
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_add_parameter(move |name| {
+        let prefix = app_handle.unwrap().get_active_instance();
+        on_profile_change_clone(&|config| {
+            active_instance_mut(config, &prefix)?.add_parameter(&name)
+        });
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_remove_parameter(move |name| {
+        let prefix = app_handle.unwrap().get_active_instance();
+        on_profile_change_clone(&|config| {
+            active_instance_mut(config, &prefix)?.remove_parameter(&name)
+        });
+    });
 
-        let mut needs_connect = needs_connect.lock().unwrap();
-        *needs_connect = true;
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_link_muscle(move |name, muscle, weight_percent| {
+        let prefix = app_handle.unwrap().get_active_instance();
+        let weight = weight_percent as f32 / 100.0;
+        on_profile_change_clone(&|config| {
+            active_instance_mut(config, &prefix)?.link_muscle(&name, &muscle, weight)
+        });
     });
 
-    app.run()
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_unlink_muscle(move |name, muscle| {
+        let prefix = app_handle.unwrap().get_active_instance();
+        on_profile_change_clone(&|config| {
+            active_instance_mut(config, &prefix)?.unlink_muscle(&name, &muscle)
+        });
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_place_parameter(move |name, point| {
+        let prefix = app_handle.unwrap().get_active_instance();
+        on_profile_change_clone(&|config| {
+            active_instance_mut(config, &prefix)?.place_parameter(&name, Some(&point))
+        });
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_unplace_parameter(move |name| {
+        let prefix = app_handle.unwrap().get_active_instance();
+        on_profile_change_clone(&|config| {
+            active_instance_mut(config, &prefix)?.place_parameter(&name, None)
+        });
+    });
+
+    let app_handle = app.as_weak();
+    let config_clone = config.clone();
+    app.on_load_curve(move |name, interaction| {
+        let app = app_handle.unwrap();
+        let config = config_clone.lock().unwrap();
+        let curve = config
+            .active_profile()
+            .instance(&app.get_active_instance())
+            .and_then(|instance| {
+                instance
+                    .parameters
+                    .iter()
+                    .find(|parameter| parameter.name == name.as_str())
+            })
+            .map(|parameter| {
+                parameter
+                    .curves
+                    .get(parse_interaction(&interaction))
+                    .clone()
+            })
+            .unwrap_or_default();
+        show_curve(&app, &curve);
+    });
+
+    let app_handle = app.as_weak();
+    app.on_preview_curve(move |kind, gamma, points| {
+        let app = app_handle.unwrap();
+        // Keep the last valid preview while the settings are being typed
+        if let Ok(curve) = parse_curve(&kind, &gamma, &points) {
+            app.set_curve_path(curve_path(&curve).into());
+        }
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_apply_curve(move |name, interaction, kind, gamma, points| {
+        let prefix = app_handle.unwrap().get_active_instance();
+        let interaction_type = parse_interaction(&interaction);
+        on_profile_change_clone(&|config| {
+            let curve = parse_curve(&kind, &gamma, &points).map_err(|message| {
+                ConfigError::InvalidCurve {
+                    name: name.to_string(),
+                    interaction: interaction_name(interaction_type),
+                    message,
+                }
+            })?;
+            active_instance_mut(config, &prefix)?.set_curve(&name, interaction_type, curve)
+        });
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_import_profile(move |path| {
+        let app = app_handle.unwrap();
+        let profile = match import_profile(Path::new(path.as_str())) {
+            Ok(profile) => profile,
+            Err(e) => {
+                app.set_config_warning(format!("Error importing profile: {}", e).into());
+                return;
+            }
+        };
+        on_profile_change_clone(&|config| {
+            let name = config.add_profile(profile.clone())?;
+            config.set_active_profile(&name)?;
+            app.set_share_status(format!("Imported profile '{}'", name).into());
+            Ok(())
+        });
+    });
+
+    let app_handle = app.as_weak();
+    let on_profile_change_clone = on_profile_change.clone();
+    app.on_export_profile(move |path| {
+        let app = app_handle.unwrap();
+        // The metadata is kept with the profile for the next export
+        on_profile_change_clone(&|config| {
+            let profile = config.active_profile_mut();
+            profile.metadata.author = app.get_share_author().to_string();
+            profile.metadata.avatar = app.get_share_avatar().to_string();
+            profile.metadata.notes = app.get_share_notes().to_string();
+            export_profile(profile, Path::new(path.as_str()))?;
+            app.set_share_status(format!("Exported profile '{}' to {}", profile.name, path).into());
+            Ok(())
+        });
+    });
+
+    let active_avatar_clone = active_avatar.clone();
+    app.on_bind_avatar(move || {
+        let avatar_id = active_avatar_clone.lock().unwrap().clone();
+        on_profile_change(&|config| match &avatar_id {
+            Some(avatar_id) => {
+                let name = config.active_profile_name().to_string();
+                config.bind_avatar(avatar_id, &name)
+            }
+            None => Ok(()),
+        });
+    });
+
+    // Avatar changes and edits to the config file happen on other threads, keep the window in sync
+    let app_handle = app.as_weak();
+    let mut shown_revision = config.lock().unwrap().revision;
+    let sync_timer = Timer::default();
+    sync_timer.start(TimerMode::Repeated, Duration::from_millis(500), move || {
+        let app = app_handle.unwrap();
+        if let Some(warning) = config_warning.lock().unwrap().take() {
+            app.set_config_warning(warning.into());
+        }
+
+        let avatar_id = active_avatar.lock().unwrap().clone().unwrap_or_default();
+        if app.get_active_avatar() != avatar_id.as_str() {
+            app.set_active_avatar(avatar_id.into());
+        }
+
+        let config = config.lock().unwrap();
+        if config.revision != shown_revision {
+            shown_revision = config.revision;
+            show_profile(&app, &config, &muscle_mappings.lock().unwrap());
+        }
+    });
+
+    app.run().map_err(std::io::Error::other)
+}
+
+fn show_profile(app: &App, config: &Config, instances: &[InstanceMappings]) {
+    let profiles = config
+        .profile_names()
+        .into_iter()
+        .map(SharedString::from)
+        .collect::<Vec<_>>();
+    app.set_profiles(ModelRc::new(VecModel::from(profiles)));
+    app.set_active_profile(config.active_profile_name().to_string().into());
+    app.set_default_profile(config.default_profile.clone().into());
+
+    app.set_ip_address(
+        config
+            .active_profile()
+            .ip_address
+            .clone()
+            .unwrap_or_default()
+            .into(),
+    );
+
+    let velocity_range = &config.active_profile().velocity_range;
+    app.set_velocity_min(velocity_range.min.to_string().into());
+    app.set_velocity_max(velocity_range.max.to_string().into());
+    app.set_velocity_clamp(velocity_range.clamp);
+    app.set_velocity_auto(velocity_range.auto_detect);
+    let depth_velocity_range = &config.active_profile().depth_velocity_range;
+    app.set_depth_velocity_min(depth_velocity_range.min.to_string().into());
+    app.set_depth_velocity_max(depth_velocity_range.max.to_string().into());
+
+    let metadata = &config.active_profile().metadata;
+    app.set_share_author(metadata.author.clone().into());
+    app.set_share_avatar(metadata.avatar.clone().into());
+    app.set_share_notes(metadata.notes.clone().into());
+
+    let scales = &config.intensity_scales;
+    app.set_scale_master((scales.master * 100.0).round() as i32);
+    app.set_scale_touch((scales.touch * 100.0).round() as i32);
+    app.set_scale_impact((scales.impact * 100.0).round() as i32);
+    app.set_scale_stab((scales.stab * 100.0).round() as i32);
+    app.set_scale_projectile((scales.projectile * 100.0).round() as i32);
+    app.set_scale_grab((scales.grab * 100.0).round() as i32);
+    app.set_mix_policy(mix_policy_name(config.mix_policy).into());
+    app.set_gestures(
+        config
+            .gestures
+            .iter()
+            .map(|gesture| GestureData {
+                name: gesture.name.clone().into(),
+                enabled: gesture.enabled,
+            })
+            .collect::<Vec<GestureData>>()
+            .as_slice()
+            .into(),
+    );
+    show_envelope(
+        app,
+        config
+            .envelopes
+            .get(parse_interaction(&app.get_envelope_interaction())),
+    );
+
+    // Keep showing the same prefab if the profile has one with that prefix
+    let prefix = app.get_active_instance();
+    let Some(instance) = instances
+        .iter()
+        .find(|instance| instance.prefix == prefix.as_str())
+        .or_else(|| instances.first())
+    else {
+        return;
+    };
+    app.set_instances(ModelRc::new(VecModel::from(
+        instances
+            .iter()
+            .map(|instance| SharedString::from(instance.prefix.as_str()))
+            .collect::<Vec<_>>(),
+    )));
+    app.set_active_instance(instance.prefix.clone().into());
+    app.set_velocity_source(velocity_source_name(instance.velocity_source).into());
+
+    app.set_muscles(
+        instance
+            .mapping
+            .parameters
+            .iter()
+            .map(|parameter| MuscleData {
+                name: parameter.name.clone().into(),
+                intensities: MuscleIntensities {
+                    touch: parameter.intensity_touch as i32,
+                    impact: parameter.intensity_impact as i32,
+                    stab: parameter.intensity_stab as i32,
+                    projectile: parameter.intensity_projectile as i32,
+                    grab: parameter.intensity_grab as i32,
+                },
+                limits: MuscleLimits {
+                    deadzone: (parameter.limits.deadzone * 100.0).round() as i32,
+                    min: parameter.limits.min_intensity as i32,
+                    max: parameter.limits.max_intensity as i32,
+                },
+                adaptation: MuscleAdaptation {
+                    enabled: parameter.adaptation.enabled,
+                    floor: (parameter.adaptation.floor * 100.0).round() as i32,
+                    fade: (parameter.adaptation.fade_ms / 1000) as i32,
+                    recover: parameter.adaptation.recover_ms as i32,
+                },
+                smoothing: show_smoothing(&parameter.smoothing),
+                links: parameter
+                    .links
+                    .iter()
+                    .map(|link| format!("{} {:.0}%", muscle_name(link.muscle), link.weight * 100.0))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+                    .into(),
+            })
+            .collect::<Vec<MuscleData>>()
+            .as_slice()
+            .into(),
+    );
+}
+
+fn show_envelope(app: &App, envelope: &Envelope) {
+    app.set_envelope_attack(envelope.attack_ms as i32);
+    app.set_envelope_decay(envelope.decay_ms as i32);
+    app.set_envelope_sustain((envelope.sustain * 100.0).round() as i32);
+    app.set_envelope_hold(envelope.hold_ms as i32);
+    app.set_envelope_release(envelope.release_ms as i32);
+}
+
+fn parse_interaction(interaction: &str) -> InteractionType {
+    match interaction {
+        "Impact" => InteractionType::Impact,
+        "Stab" => InteractionType::Stab,
+        "Projectile" => InteractionType::Projectile,
+        "Grab" => InteractionType::Grab,
+        _ => InteractionType::Touch,
+    }
+}
+
+fn parse_smoothing(smoothing: &MuscleSmoothing) -> Smoothing {
+    let filter = match smoothing.filter.as_str() {
+        "EMA" => DepthFilter::Ema {
+            factor: smoothing.factor as f32 / 100.0,
+        },
+        "One euro" => DepthFilter::OneEuro {
+            min_cutoff: smoothing.cutoff,
+            beta: smoothing.beta,
+        },
+        _ => DepthFilter::None,
+    };
+    Smoothing {
+        filter,
+        peak_threshold: smoothing.peak as f32 / 100.0,
+    }
+}
+
+// The sliders of the filters that are not selected keep their defaults
+fn show_smoothing(smoothing: &Smoothing) -> MuscleSmoothing {
+    let (filter, factor, cutoff, beta) = match smoothing.filter {
+        DepthFilter::None => ("None", DEFAULT_EMA_FACTOR, DEFAULT_MIN_CUTOFF, DEFAULT_BETA),
+        DepthFilter::Ema { factor } => ("EMA", factor, DEFAULT_MIN_CUTOFF, DEFAULT_BETA),
+        DepthFilter::OneEuro { min_cutoff, beta } => {
+            ("One euro", DEFAULT_EMA_FACTOR, min_cutoff, beta)
+        }
+    };
+    MuscleSmoothing {
+        filter: filter.into(),
+        factor: (factor * 100.0).round() as i32,
+        cutoff,
+        beta,
+        peak: (smoothing.peak_threshold * 100.0).round() as i32,
+    }
+}
+
+fn parse_mix_policy(policy: &str) -> MixPolicy {
+    match policy {
+        "Winner takes all" => MixPolicy::WinnerTakesAll,
+        "Blend" => MixPolicy::Blend,
+        _ => MixPolicy::PerMuscleMax,
+    }
+}
+
+fn mix_policy_name(policy: MixPolicy) -> &'static str {
+    match policy {
+        MixPolicy::WinnerTakesAll => "Winner takes all",
+        MixPolicy::PerMuscleMax => "Per muscle max",
+        MixPolicy::Blend => "Blend",
+    }
+}
+
+fn parse_velocity_source(source: &str) -> VelocitySource {
+    match source {
+        "Depth" => VelocitySource::Depth,
+        "Both" => VelocitySource::Both,
+        _ => VelocitySource::Contacts,
+    }
+}
+
+fn velocity_source_name(source: VelocitySource) -> &'static str {
+    match source {
+        VelocitySource::Contacts => "Contacts",
+        VelocitySource::Depth => "Depth",
+        VelocitySource::Both => "Both",
+    }
+}
+
+// Points are written as "input output" pairs separated by commas, like "0 0, 0.5 0.2, 1 1"
+fn parse_curve(kind: &str, gamma: &str, points: &str) -> Result<ResponseCurve, String> {
+    let curve = match kind {
+        "Gamma" => ResponseCurve::Gamma {
+            gamma: gamma
+                .trim()
+                .parse()
+                .map_err(|_| format!("'{}' is not a number", gamma))?,
+        },
+        "Smoothstep" => ResponseCurve::Smoothstep,
+        "Piecewise" => ResponseCurve::Piecewise {
+            points: points
+                .split(',')
+                .map(|point| {
+                    let values = point
+                        .split_whitespace()
+                        .map(str::parse::<f32>)
+                        .collect::<Result<Vec<_>, _>>();
+                    match values.as_deref() {
+                        Ok([input, output]) => Ok([*input, *output]),
+                        _ => Err(format!("'{}' is not an input and output", point.trim())),
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+        },
+        _ => ResponseCurve::Linear,
+    };
+    curve.validate()?;
+    Ok(curve)
+}
+
+fn show_curve(app: &App, curve: &ResponseCurve) {
+    let kind = match curve {
+        ResponseCurve::Linear => "Linear",
+        ResponseCurve::Gamma { gamma } => {
+            app.set_curve_gamma(gamma.to_string().into());
+            "Gamma"
+        }
+        ResponseCurve::Smoothstep => "Smoothstep",
+        ResponseCurve::Piecewise { points } => {
+            let points = points
+                .iter()
+                .map(|[input, output]| format!("{} {}", input, output))
+                .collect::<Vec<_>>()
+                .join(", ");
+            app.set_curve_points(points.into());
+            "Piecewise"
+        }
+    };
+    app.set_curve_kind(kind.into());
+    app.set_curve_path(curve_path(curve).into());
+}
+
+// SVG path of the curve in a 100 by 100 box with the origin at the bottom left
+fn curve_path(curve: &ResponseCurve) -> String {
+    const SAMPLES: usize = 50;
+    (0..=SAMPLES)
+        .map(|i| {
+            let input = i as f32 / SAMPLES as f32;
+            let output = curve.evaluate(input).clamp(0.0, 1.0);
+            let command = if i == 0 { "M" } else { "L" };
+            format!(
+                "{} {:.1} {:.1}",
+                command,
+                input * 100.0,
+                100.0 - output * 100.0
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn active_instance_mut<'a>(
+    config: &'a mut Config,
+    prefix: &str,
+) -> Result<&'a mut InstanceConfig, ConfigError> {
+    config
+        .active_profile_mut()
+        .instance_mut(prefix)
+        .ok_or_else(|| ConfigError::UnknownPrefix(prefix.to_string()))
+}
+
+fn save(config: &Config) {
+    request_save(config);
 }